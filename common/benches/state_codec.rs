@@ -0,0 +1,61 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use game_common::{
+    game_config::GameConfig,
+    game_state::{GameState, NextTurn},
+    player_move::PlayerMove,
+    point::Point,
+};
+
+// A big game, where the size of states matters the most.
+fn big_state(num_players: usize) -> GameState {
+    let mut state = GameState::new("game-bench", 42, GameConfig::default());
+    for turn in 0..20 {
+        for i in 0..num_players {
+            state.apply_move(PlayerMove {
+                name: format!("player-{i}"),
+                target: Point {
+                    x: ((turn * 37 + i * 500) % 5000) as i32,
+                    y: ((turn * 11 + i * 300) % 5000) as i32,
+                },
+            });
+        }
+        state = match state.next_turn() {
+            NextTurn::GameState(state) => state,
+            NextTurn::FinalResults(_) => unreachable!(),
+        };
+    }
+    state
+}
+
+fn bench_codecs(c: &mut Criterion) {
+    let state = big_state(100);
+    let text = state.to_string();
+    let json = state.to_json();
+    let binary = state.to_binary();
+    println!(
+        "State with {} players and {} items: text {} bytes, json {} bytes, binary {} bytes",
+        state.players.len(),
+        state.items.len(),
+        text.len(),
+        json.len(),
+        binary.len()
+    );
+
+    c.bench_function("encode text", |b| b.iter(|| black_box(&state).to_string()));
+    c.bench_function("encode json", |b| b.iter(|| black_box(&state).to_json()));
+    c.bench_function("encode binary", |b| {
+        b.iter(|| black_box(&state).to_binary())
+    });
+    c.bench_function("decode text", |b| {
+        b.iter(|| GameState::from_string(black_box(&text)).unwrap())
+    });
+    c.bench_function("decode json", |b| {
+        b.iter(|| GameState::from_json(black_box(&json)).unwrap())
+    });
+    c.bench_function("decode binary", |b| {
+        b.iter(|| GameState::from_binary(black_box(&binary)).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_codecs);
+criterion_main!(benches);
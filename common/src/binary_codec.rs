@@ -0,0 +1,399 @@
+// Compact binary encoding of `GameState`, contains the same fields as the text format.
+//
+// All integers are varints (7 bits per byte, the highest bit is set if more bytes follow),
+// signed integers are zigzag-encoded first, so small negative numbers are short too.
+// Strings are encoded as [LENGTH] [UTF-8 BYTES].
+//
+// [FORMAT_VERSION] [TURN] [MAX_TURNS] [WIDTH] [HEIGHT] [TARGET_WIDTH] [TARGET_HEIGHT] [GAME_ID]
+// [NUM_PLAYERS]
+// [NAME] [SCORE] [X] [Y] [R] [VX] [VY] [TARGET_X] [TARGET_Y] [BOOST_TURNS] [IDLE_TURNS]
+// ... ([NUM_PLAYERS - 1] more players)
+// [NUM_ITEMS]
+// [X] [Y] [R] [KIND] [VX] [VY]             <- kind is an index in `ItemKind::ALL`
+// ... ([NUM_ITEMS - 1] more items)
+// [NUM_OBSTACLES]
+// 0 [X] [Y] [R] or 1 [X1] [Y1] [X2] [Y2]   <- circle or rectangle
+// ... ([NUM_OBSTACLES - 1] more obstacles)
+//
+// Spectator messages (see `state_delta`) start with [TAG]: 0 is followed by the full state as above,
+// 1 by the delta (obstacles never change, so they are only in the full state):
+// [FORMAT_VERSION] [TURN] [WIDTH] [HEIGHT] [TARGET_WIDTH] [TARGET_HEIGHT]
+// [NUM_PLAYERS] [PLAYER]...                <- same as in the state
+// [NUM_REMOVED_ITEMS] [INDEX]...           <- difference with the previous index, the first one as is
+// [NUM_UPDATED_ITEMS] [INDEX] [ITEM]...    <- indices are encoded the same way
+// [NUM_ADDED_ITEMS] [ITEM]...
+
+use anyhow::{bail, Context};
+
+use crate::game_state::{GameState, Item, Player};
+use crate::item_kind::ItemKind;
+use crate::obstacle::Obstacle;
+use crate::point::Point;
+use crate::state_delta::{SpectatorMessage, StateDelta};
+
+const FORMAT_VERSION: u64 = 6;
+const TAG_KEYFRAME: u8 = 0;
+const TAG_DELTA: u8 = 1;
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u64(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.u64(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn point(&mut self, p: Point) {
+        self.i64(p.x as i64);
+        self.i64(p.y as i64);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn players(&mut self, players: &[Player]) {
+        self.u64(players.len() as u64);
+        for player in players.iter() {
+            self.str(&player.name);
+            self.i64(player.score);
+            self.point(player.pos);
+            self.i64(player.radius as i64);
+            self.point(player.speed);
+            self.point(player.target);
+            self.u64(player.boost_turns as u64);
+            self.u64(player.idle_turns as u64);
+        }
+    }
+
+    fn item(&mut self, item: &Item) {
+        self.point(item.pos);
+        self.i64(item.radius as i64);
+        let kind = ItemKind::ALL.iter().position(|&kind| kind == item.kind);
+        self.u64(kind.expect("All kinds are in ItemKind::ALL") as u64);
+        self.point(item.speed);
+    }
+
+    fn items(&mut self, items: &[Item]) {
+        self.u64(items.len() as u64);
+        for item in items.iter() {
+            self.item(item);
+        }
+    }
+
+    // Increasing indices, each one as a difference with the previous one.
+    fn indices(&mut self, indices: impl ExactSizeIterator<Item = usize>) {
+        self.u64(indices.len() as u64);
+        let mut prev = 0;
+        for idx in indices {
+            self.u64((idx - prev) as u64);
+            prev = idx;
+        }
+    }
+
+    fn obstacles(&mut self, obstacles: &[Obstacle]) {
+        self.u64(obstacles.len() as u64);
+        for obstacle in obstacles.iter() {
+            match *obstacle {
+                Obstacle::Circle { center, radius } => {
+                    self.u64(0);
+                    self.point(center);
+                    self.i64(radius as i64);
+                }
+                Obstacle::Rect { min, max } => {
+                    self.u64(1);
+                    self.point(min);
+                    self.point(max);
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        let Some(&byte) = self.buf.get(self.pos) else {
+            bail!("Unexpected end of binary state");
+        };
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        let mut res = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= 64 {
+                bail!("Varint is too long");
+            }
+            res |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(res);
+            }
+            shift += 7;
+        }
+    }
+
+    fn i64(&mut self) -> anyhow::Result<i64> {
+        let value = self.u64()?;
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(self.i64()?.try_into()?)
+    }
+
+    fn usize(&mut self) -> anyhow::Result<usize> {
+        Ok(self.u64()?.try_into()?)
+    }
+
+    fn point(&mut self) -> anyhow::Result<Point> {
+        Ok(Point {
+            x: self.i32()?,
+            y: self.i32()?,
+        })
+    }
+
+    fn str(&mut self) -> anyhow::Result<String> {
+        let len = self.usize()?;
+        let Some(bytes) = self.buf.get(self.pos..self.pos.saturating_add(len)) else {
+            bail!("Unexpected end of binary state");
+        };
+        self.pos += len;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    fn players(&mut self) -> anyhow::Result<Vec<Player>> {
+        let num_players = self.usize().context("num players")?;
+        let mut players = vec![];
+        for _ in 0..num_players {
+            players.push(Player {
+                name: self.str()?,
+                score: self.i64()?,
+                pos: self.point()?,
+                radius: self.i32()?,
+                speed: self.point()?,
+                target: self.point()?,
+                boost_turns: self.usize()?,
+                idle_turns: self.usize()?,
+            });
+        }
+        Ok(players)
+    }
+
+    fn item(&mut self) -> anyhow::Result<Item> {
+        let pos = self.point()?;
+        let radius = self.i32()?;
+        let Some(&kind) = ItemKind::ALL.get(self.usize()?) else {
+            bail!("Unknown item kind");
+        };
+        let speed = self.point()?;
+        Ok(Item {
+            pos,
+            radius,
+            kind,
+            speed,
+        })
+    }
+
+    fn items(&mut self) -> anyhow::Result<Vec<Item>> {
+        let num_items = self.usize().context("num items")?;
+        let mut items = vec![];
+        for _ in 0..num_items {
+            items.push(self.item()?);
+        }
+        Ok(items)
+    }
+
+    fn indices(&mut self) -> anyhow::Result<Vec<usize>> {
+        let num_indices = self.usize().context("num indices")?;
+        let mut indices = vec![];
+        let mut prev: usize = 0;
+        for _ in 0..num_indices {
+            prev = prev.saturating_add(self.usize()?);
+            indices.push(prev);
+        }
+        Ok(indices)
+    }
+
+    fn obstacles(&mut self) -> anyhow::Result<Vec<Obstacle>> {
+        let num_obstacles = self.usize().context("num obstacles")?;
+        let mut obstacles = vec![];
+        for _ in 0..num_obstacles {
+            obstacles.push(match self.u64()? {
+                0 => Obstacle::Circle {
+                    center: self.point()?,
+                    radius: self.i32()?,
+                },
+                1 => Obstacle::Rect {
+                    min: self.point()?,
+                    max: self.point()?,
+                },
+                shape => bail!("Unknown obstacle shape {shape}"),
+            });
+        }
+        Ok(obstacles)
+    }
+
+    fn version(&mut self) -> anyhow::Result<()> {
+        let version = self.u64()?;
+        if version != FORMAT_VERSION {
+            bail!("Unsupported binary state version {version}");
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> anyhow::Result<()> {
+        if self.pos != self.buf.len() {
+            bail!("Extra bytes after the binary state");
+        }
+        Ok(())
+    }
+}
+
+impl GameState {
+    fn write(&self, w: &mut Writer) {
+        w.u64(FORMAT_VERSION);
+        w.u64(self.turn as u64);
+        w.u64(self.max_turns as u64);
+        w.i64(self.width as i64);
+        w.i64(self.height as i64);
+        w.i64(self.target_width as i64);
+        w.i64(self.target_height as i64);
+        w.str(&self.game_id);
+        w.players(&self.players);
+        w.items(&self.items);
+        w.obstacles(&self.obstacles);
+    }
+
+    fn read_binary(r: &mut Reader) -> anyhow::Result<Self> {
+        r.version()?;
+        let turn = r.usize()?;
+        let max_turns = r.usize()?;
+        let width = r.i32()?;
+        let height = r.i32()?;
+        let target_width = r.i32()?;
+        let target_height = r.i32()?;
+        let game_id = r.str()?;
+        let mut res = Self::without_players(game_id, turn, max_turns, width, height);
+        res.target_width = target_width;
+        res.target_height = target_height;
+        res.players = r.players()?;
+        res.items = r.items()?;
+        res.obstacles = r.obstacles()?;
+        Ok(res)
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        self.write(&mut w);
+        w.buf
+    }
+
+    pub fn from_binary(buf: &[u8]) -> anyhow::Result<Self> {
+        let mut r = Reader { buf, pos: 0 };
+        let res = Self::read_binary(&mut r)?;
+        r.finish()?;
+        Ok(res)
+    }
+}
+
+impl SpectatorMessage {
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        match self {
+            SpectatorMessage::Keyframe(state) => {
+                w.buf.push(TAG_KEYFRAME);
+                state.write(&mut w);
+            }
+            SpectatorMessage::Delta(delta) => {
+                w.buf.push(TAG_DELTA);
+                w.u64(FORMAT_VERSION);
+                w.u64(delta.turn as u64);
+                w.i64(delta.width as i64);
+                w.i64(delta.height as i64);
+                w.i64(delta.target_width as i64);
+                w.i64(delta.target_height as i64);
+                w.players(&delta.players);
+                w.indices(delta.removed_items.iter().copied());
+                w.indices(delta.updated_items.iter().map(|(idx, _)| *idx));
+                for (_, item) in delta.updated_items.iter() {
+                    w.item(item);
+                }
+                w.items(&delta.added_items);
+            }
+        }
+        w.buf
+    }
+
+    pub fn from_binary(buf: &[u8]) -> anyhow::Result<Self> {
+        let mut r = Reader { buf, pos: 0 };
+        let res = match r.byte()? {
+            TAG_KEYFRAME => SpectatorMessage::Keyframe(GameState::read_binary(&mut r)?),
+            TAG_DELTA => {
+                r.version()?;
+                let turn = r.usize()?;
+                let width = r.i32()?;
+                let height = r.i32()?;
+                let target_width = r.i32()?;
+                let target_height = r.i32()?;
+                let players = r.players()?;
+                let removed_items = r.indices()?;
+                let mut updated_items = vec![];
+                for idx in r.indices()? {
+                    updated_items.push((idx, r.item()?));
+                }
+                SpectatorMessage::Delta(StateDelta {
+                    turn,
+                    width,
+                    height,
+                    target_width,
+                    target_height,
+                    players,
+                    removed_items,
+                    updated_items,
+                    added_items: r.items()?,
+                })
+            }
+            tag => bail!("Unknown spectator message tag {tag}"),
+        };
+        r.finish()?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn binary_round_trip() {
+    let text = "TURN 3 600 2000 1500 game-1
+2
+alice -5 100 200 20 -3 4 -150 250
+bob 0 1000 700 20 0 0 2147483647 -2147483648
+1
+300 400 25
+END_STATE
+";
+    let state = GameState::from_string(text).unwrap();
+    let binary = state.to_binary();
+    // Binary states contain everything, so compare with the text format with all optional parts.
+    let full_text = state.to_text(crate::game_state::TextFormat::all());
+    assert!(binary.len() < full_text.len() / 2);
+    assert_eq!(GameState::from_binary(&binary).unwrap().to_string(), text);
+    assert!(GameState::from_binary(&binary[..binary.len() - 1]).is_err());
+}
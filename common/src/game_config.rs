@@ -0,0 +1,336 @@
+use std::fmt;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::consts::{
+    MAX_ACC, MAX_ITEMS, MAX_ITEM_R, MAX_SPEED, MAX_TURNS, MIN_ITEM_R, PLAYER_RADIUS, START_HEIGHT,
+    START_MAX_PLAYERS, START_WIDTH, TURN_WAIT_TIME,
+};
+use crate::game_state::TokenReader;
+
+// Who gets an item if several players touched it during the same turn.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemTieBreak {
+    // The player who touched it first. If several players touched it at the same moment,
+    // the one listed earlier in the state.
+    #[default]
+    EarliestContact,
+    // The player whose centre is the closest to the item at the end of the turn,
+    // ties are resolved as in `EarliestContact`.
+    ClosestCentre,
+    // Points are split evenly between all players who touched it, the remaining points
+    // are given one by one in the `EarliestContact` order. Needs `item_points` of at least 2,
+    // otherwise it is the same as `EarliestContact`.
+    Split,
+}
+
+// Rules of the game. Defaults are the values from `consts`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    pub max_acc: f64,
+    pub max_speed: f64,
+    pub max_items: usize,
+    pub min_item_r: i32,
+    pub max_item_r: i32,
+    pub player_radius: i32,
+    pub start_width: i32,
+    pub start_height: i32,
+    // if more players play, field becomes bigger
+    pub start_max_players: usize,
+    pub max_turns: usize,
+    pub turn_wait_ms: u64,
+    // Players bounce off each other like billiard balls, mass is proportional to the area.
+    pub player_collisions: bool,
+    // Score for one item.
+    pub item_points: i64,
+    pub item_tie_break: ItemTieBreak,
+    // Items of different kinds appear, see `ItemKind`.
+    pub item_kinds: bool,
+    pub gem_points: i64,
+    pub decay_per_turn: i32,
+    pub boost_turns: usize,
+    pub boost_max_speed: f64,
+    pub radius_change: i32,
+    pub min_player_radius: i32,
+    pub max_player_radius: i32,
+    // Number of static obstacles, see `Obstacle`. Sizes are sides of rectangles or diameters of circles.
+    pub obstacles: usize,
+    pub min_obstacle_size: i32,
+    pub max_obstacle_size: i32,
+    // Items fly with a random speed (each component is at most `max_item_speed`) and bounce off the sides.
+    pub moving_items: bool,
+    pub max_item_speed: i32,
+    // How much the width and the height of the field could change in one turn, 0 means instantly.
+    pub resize_step: i32,
+    // Players who didn't move for more than that many turns leave the game, 0 means never.
+    pub max_idle_turns: usize,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            max_acc: MAX_ACC,
+            max_speed: MAX_SPEED,
+            max_items: MAX_ITEMS,
+            min_item_r: MIN_ITEM_R,
+            max_item_r: MAX_ITEM_R,
+            player_radius: PLAYER_RADIUS,
+            start_width: START_WIDTH,
+            start_height: START_HEIGHT,
+            start_max_players: START_MAX_PLAYERS,
+            max_turns: MAX_TURNS,
+            turn_wait_ms: TURN_WAIT_TIME.as_millis() as u64,
+            player_collisions: false,
+            item_points: 1,
+            item_tie_break: ItemTieBreak::EarliestContact,
+            item_kinds: false,
+            gem_points: 5,
+            decay_per_turn: 1,
+            boost_turns: 20,
+            boost_max_speed: 150.0,
+            radius_change: 5,
+            min_player_radius: 10,
+            max_player_radius: 40,
+            obstacles: 0,
+            min_obstacle_size: 50,
+            max_obstacle_size: 300,
+            moving_items: false,
+            max_item_speed: 10,
+            resize_step: 0,
+            max_idle_turns: 0,
+        }
+    }
+}
+
+impl GameConfig {
+    // Loads rules from the JSON file (missing fields are default), and then applies
+    // `rules` in the `[KEY]=[VALUE]` format on top.
+    pub fn load(path: Option<&str>, rules: &[String]) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .context(format!("Failed to read config {path}"))?;
+                serde_json::from_str(&content).context(format!("Failed to parse config {path}"))?
+            }
+            None => Self::default(),
+        };
+        for rule in rules.iter() {
+            let Some((key, value)) = rule.split_once('=') else {
+                bail!("Expected rule in the [KEY]=[VALUE] format, got '{rule}'");
+            };
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn to_map(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => unreachable!("GameConfig is always serialized as an object"),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        let mut map = self.to_map();
+        if !map.contains_key(key) {
+            bail!("Unknown rule '{key}'");
+        }
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()));
+        map.insert(key.to_owned(), value);
+        *self = serde_json::from_value(Value::Object(map))
+            .context(format!("Wrong value for rule '{key}'"))?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let positive = |value: f64| value.is_finite() && value > 0.0;
+        if !positive(self.max_acc) || !positive(self.max_speed) {
+            bail!("max_acc and max_speed should be positive");
+        }
+        if self.min_item_r <= 0 || self.min_item_r >= self.max_item_r {
+            bail!("Expected 0 < min_item_r < max_item_r");
+        }
+        if self.player_radius <= 0 {
+            bail!("player_radius should be positive");
+        }
+        let min_side = 2 * self.max_item_r.max(self.max_player_radius);
+        if self.start_width <= min_side || self.start_height <= min_side {
+            bail!("Field is too small for items and players");
+        }
+        // Items are placed at random places where they don't overlap, so they should
+        // cover only a small part of the field, otherwise there could be no place left.
+        let items_area = self.max_items as i64 * (2 * self.max_item_r as i64).pow(2);
+        if 2 * items_area > self.start_width as i64 * self.start_height as i64 {
+            bail!("max_items is too big for the field");
+        }
+        if self.item_points <= 0 || self.gem_points <= 0 {
+            bail!("item_points and gem_points should be positive");
+        }
+        // Shrink and Grow items change the radius by `radius_change` in opposite directions, so with
+        // a negative one players could get outside of the radius bounds.
+        if self.radius_change < 0 {
+            bail!("radius_change should not be negative");
+        }
+        if self.decay_per_turn <= 0 || self.boost_turns == 0 || !positive(self.boost_max_speed) {
+            bail!("decay_per_turn, boost_turns and boost_max_speed should be positive");
+        }
+        if self.min_player_radius <= 0
+            || self.min_player_radius > self.player_radius
+            || self.player_radius > self.max_player_radius
+        {
+            bail!("Expected 0 < min_player_radius <= player_radius <= max_player_radius");
+        }
+        if self.obstacles > 0 {
+            if self.min_obstacle_size < 2 || self.min_obstacle_size > self.max_obstacle_size {
+                bail!("Expected 2 <= min_obstacle_size <= max_obstacle_size");
+            }
+            // Obstacles keep a gap from the sides, so the biggest player could fly around them.
+            let min_side = self.max_obstacle_size + 4 * self.max_player_radius + 2;
+            if self.start_width <= min_side || self.start_height <= min_side {
+                bail!("Field is too small for obstacles");
+            }
+        }
+        // Items are reflected off a side only once per step, so an item which moves further than
+        // the distance between the opposite sides would end up behind the other side. The field
+        // is never smaller than at the start.
+        if self.moving_items {
+            let min_span = self.start_width.min(self.start_height) - 2 * self.max_item_r;
+            if self.max_item_speed <= 0 || self.max_item_speed >= min_span {
+                bail!(
+                    "Expected 0 < max_item_speed < min(start_width, start_height) - 2 * max_item_r"
+                );
+            }
+        }
+        if self.resize_step < 0 {
+            bail!("resize_step should not be negative");
+        }
+        if self.max_turns == 0 || self.start_max_players == 0 {
+            bail!("max_turns and start_max_players should be positive");
+        }
+        Ok(())
+    }
+
+    pub fn from_string(s: &str) -> anyhow::Result<Self> {
+        Self::read(&mut TokenReader::new(s))
+    }
+
+    pub(crate) fn read(tokens: &mut TokenReader) -> anyhow::Result<Self> {
+        let cmd_word: String = tokens.next("CONFIG")?;
+        if cmd_word != "CONFIG" {
+            bail!("Expected CONFIG, got {}", cmd_word);
+        }
+        let mut res = Self::default();
+        loop {
+            let key: String = tokens.next("rule name")?;
+            if key == "END_CONFIG" {
+                res.validate()?;
+                return Ok(res);
+            }
+            let value: String = tokens.next("rule value")?;
+            res.set(&key, &value)?;
+        }
+    }
+}
+
+// CONFIG
+// [KEY] [VALUE]
+// ... (one line for each rule)
+// END_CONFIG
+impl fmt::Display for GameConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "CONFIG")?;
+        for (key, value) in self.to_map() {
+            match value {
+                Value::String(value) => writeln!(f, "{key} {value}")?,
+                value => writeln!(f, "{key} {value}")?,
+            }
+        }
+        writeln!(f, "END_CONFIG")
+    }
+}
+
+#[test]
+fn config_text_round_trip() {
+    let mut config = GameConfig::default();
+    config.set("max_turns", "100").unwrap();
+    config.set("max_acc", "12.5").unwrap();
+    config.set("item_tie_break", "closest_centre").unwrap();
+    assert_eq!(config.item_tie_break, ItemTieBreak::ClosestCentre);
+    assert!(config.set("item_tie_break", "random").is_err());
+    assert!(config.set("max_turn", "100").is_err());
+    assert!(config.set("max_turns", "-1").is_err());
+    assert_eq!(
+        GameConfig::from_string(&config.to_string()).unwrap(),
+        config
+    );
+}
+
+#[test]
+fn config_validation() {
+    assert!(GameConfig::default().validate().is_ok());
+    let mut config = GameConfig::default();
+    config.set("max_speed", "0").unwrap();
+    assert!(config.validate().is_err());
+    let config = GameConfig {
+        max_acc: f64::NAN,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+    let mut config = GameConfig::default();
+    config.set("max_items", "1000").unwrap();
+    assert!(config.validate().is_err());
+    let mut config = GameConfig::default();
+    config.set("moving_items", "true").unwrap();
+    assert!(config.validate().is_ok());
+    // Fast items are fine while one step is shorter than 1500 - 2 * 100 between the sides.
+    config.set("max_item_speed", "1299").unwrap();
+    assert!(config.validate().is_ok());
+    // Otherwise one reflection could leave the item outside of the field.
+    config.set("max_item_speed", "1300").unwrap();
+    assert!(config.validate().is_err());
+    config.set("max_item_speed", "0").unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn split_is_allowed_with_one_item_point() {
+    // It is the same as `EarliestContact` then, see `tie_break_split`.
+    let config = GameConfig::load(None, &["item_tie_break=split".to_owned()]).unwrap();
+    assert_eq!(config.item_tie_break, ItemTieBreak::Split);
+    assert_eq!(config.item_points, 1);
+}
+
+#[test]
+fn item_kind_rules_validation() {
+    let invalid = [
+        "radius_change=-800",
+        "decay_per_turn=0",
+        "decay_per_turn=-1",
+        "boost_turns=0",
+        "boost_max_speed=0",
+        "boost_max_speed=-10",
+    ];
+    for rule in invalid {
+        let rules = [
+            "item_kinds=true".to_owned(),
+            "player_collisions=true".to_owned(),
+            rule.to_owned(),
+        ];
+        assert!(GameConfig::load(None, &rules).is_err(), "{rule}");
+    }
+    let config = GameConfig {
+        boost_max_speed: f64::INFINITY,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+    let config = GameConfig {
+        radius_change: 0,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+}
@@ -1,17 +1,21 @@
 use std::collections::VecDeque;
+use std::fmt;
 use std::str::FromStr;
 
-use crate::consts::{
-    MAX_ACC, MAX_ITEMS, MAX_ITEM_R, MAX_SPEED, MAX_TURNS, MIN_ITEM_R, PLAYER_RADIUS, START_HEIGHT,
-    START_MAX_PLAYERS, START_WIDTH,
-};
+use crate::game_config::{GameConfig, ItemTieBreak};
+use crate::item_kind::ItemKind;
+use crate::obstacle::{self, Obstacle};
 use crate::player_move::PlayerMove;
 use crate::point::Point;
 use anyhow::{anyhow, bail};
-use rand::seq::SliceRandom;
-use rand::{thread_rng, Rng};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug)]
+// Random placement gives up after that many attempts, so a crowded field can't hang the game.
+const MAX_PLACEMENT_ATTEMPTS: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Player {
     pub name: String,
     pub pos: Point,
@@ -19,13 +23,24 @@ pub struct Player {
     pub target: Point,
     pub score: i64,
     pub radius: i32,
+    // Turns left with the increased max speed, see `ItemKind::SpeedBoost`.
+    #[serde(default)]
+    pub boost_turns: usize,
+    // Turns since the last move of the player, always zero unless `max_idle_turns` rule is set.
+    #[serde(default)]
+    pub idle_turns: usize,
     // TODO: contact info?
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Item {
     pub pos: Point,
     pub radius: i32,
+    #[serde(default)]
+    pub kind: ItemKind,
+    // Always zero unless `moving_items` rule is enabled.
+    #[serde(default)]
+    pub speed: Point,
 }
 
 impl Item {
@@ -42,17 +57,58 @@ impl Item {
     }
 }
 
-#[derive(Clone)]
+// Besides the text format (see `Display`), could be encoded as JSON with the same fields.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GameState {
     pub width: i32,
     pub height: i32,
+    // Size the field is changing to, see `resize_step` rule. Same as the current size if it doesn't change.
+    #[serde(default)]
+    pub target_width: i32,
+    #[serde(default)]
+    pub target_height: i32,
     pub turn: usize,
     pub max_turns: usize,
     pub players: Vec<Player>,
     pub items: Vec<Item>,
+    // Never change during the game.
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    // Players who left the game. They keep their scores and come back with the next move.
+    // Like in the text and binary formats, they are not sent to players.
+    #[serde(skip)]
+    pub inactive_players: Vec<Player>,
     pub game_id: String,
+    // All randomness of the game comes from `rng`, so replaying the same moves
+    // against the same seed produces exactly the same states.
+    // The seed is not part of the text format, otherwise bots could predict new items.
+    #[serde(skip)]
+    pub seed: u64,
+    #[serde(skip, default = "default_rng")]
+    rng: Box<ChaCha8Rng>,
+    // Not part of the text format either, use `CONFIG` command to get it from the server.
+    #[serde(skip)]
+    pub config: Box<GameConfig>,
+}
+
+// States parsed from text or JSON are only used for displaying.
+fn default_rng() -> Box<ChaCha8Rng> {
+    Box::new(ChaCha8Rng::seed_from_u64(0))
+}
+
+// Everything needed to continue the game later, e.g. after restarting the server.
+// Unlike the JSON sent to players, it contains the seed, the rng, the config and inactive players.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    state: GameState,
+    seed: u64,
+    rng: ChaCha8Rng,
+    config: GameConfig,
+    #[serde(default)]
+    inactive_players: Vec<Player>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct GameResults {
     pub players: Vec<Player>,
     pub game_id: String,
@@ -61,12 +117,59 @@ pub struct GameResults {
 impl GameResults {
     pub fn new(state: GameState) -> Self {
         let mut players = state.players;
+        players.extend(state.inactive_players);
         players.sort_by_key(|player| -player.score);
         Self {
             players,
             game_id: state.game_id,
         }
     }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("GameResults is always serializable")
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+// Optional parts of the text format. Old clients get none of them, so they could parse states as before.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TextFormat {
+    // `[BOOST_TURNS]` at the end of player lines and `[KIND]` at the end of item lines.
+    pub item_kinds: bool,
+    // `[NUM_OBSTACLES]` and obstacles after the items.
+    pub obstacles: bool,
+    // `[VX] [VY]` at the end of item lines, after the kind.
+    pub item_speed: bool,
+    // `[TARGET_WIDTH] [TARGET_HEIGHT]` line after the `TURN` line.
+    pub target_size: bool,
+    // `[IDLE_TURNS]` at the end of player lines, after `[BOOST_TURNS]`.
+    pub idle_turns: bool,
+}
+
+impl TextFormat {
+    // Everything which could be non-default with these rules.
+    pub fn for_config(config: &GameConfig) -> Self {
+        Self {
+            item_kinds: config.item_kinds,
+            obstacles: config.obstacles > 0,
+            item_speed: config.moving_items,
+            target_size: config.resize_step > 0,
+            idle_turns: config.max_idle_turns > 0,
+        }
+    }
+
+    pub fn all() -> Self {
+        Self {
+            item_kinds: true,
+            obstacles: true,
+            item_speed: true,
+            target_size: true,
+            idle_turns: true,
+        }
+    }
 }
 
 pub enum NextTurn {
@@ -84,7 +187,7 @@ fn clamp(pos: &mut i32, speed: &mut i32, min_pos: i32, max_pos: i32) {
     }
 }
 
-struct TokenReader {
+pub(crate) struct TokenReader {
     tokens: VecDeque<String>,
 }
 
@@ -100,24 +203,44 @@ impl TokenReader {
         T: FromStr,
         <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
     {
-        Ok(self
-            .tokens
+        self.tokens
             .pop_front()
             .ok_or_else(|| anyhow!(err_msg.to_owned()))?
             .parse()
-            .map_err(|err| anyhow!("Failed to parse '{err_msg}': {err:?}"))?)
+            .map_err(|err| anyhow!("Failed to parse '{err_msg}': {err:?}"))
+    }
+
+    pub fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|s| s.as_str())
     }
 }
 
-pub fn next_turn_player_state(player: &mut Player, width: i32, height: i32) {
+fn update_player_speed(player: &mut Player, config: &GameConfig) {
     let mut acc = player.target - player.pos;
-    if acc.len() > MAX_ACC {
-        acc = acc.scale(MAX_ACC);
+    if acc.len() > config.max_acc {
+        acc = acc.scale(config.max_acc);
     }
     player.speed += acc;
-    if player.speed.len() > MAX_SPEED {
-        player.speed = player.speed.scale(MAX_SPEED);
+    let max_speed = if player.boost_turns > 0 {
+        player.boost_turns -= 1;
+        config.boost_max_speed
+    } else {
+        config.max_speed
+    };
+    if player.speed.len() > max_speed {
+        player.speed = player.speed.scale(max_speed);
     }
+}
+
+// Returns the movement of the player during the turn, before bouncing off the sides.
+pub fn next_turn_player_state(
+    player: &mut Player,
+    config: &GameConfig,
+    width: i32,
+    height: i32,
+) -> Point {
+    update_player_speed(player, config);
+    let movement = player.speed;
     player.pos += player.speed;
     clamp(
         &mut player.pos.x,
@@ -131,60 +254,389 @@ pub fn next_turn_player_state(player: &mut Player, width: i32, height: i32) {
         player.radius,
         height - player.radius,
     );
+    movement
+}
+
+// Same as `clamp`, but for positions in the middle of the turn.
+fn fold(pos: f64, min_pos: f64, max_pos: f64) -> f64 {
+    if pos < min_pos {
+        2.0 * min_pos - pos
+    } else if pos > max_pos {
+        2.0 * max_pos - pos
+    } else {
+        pos
+    }
+}
+
+// The smallest `s` from [0, 1] such that the point `a + s * (b - a)` is within `radius` from `center`.
+pub(crate) fn first_touch(a: (f64, f64), b: (f64, f64), center: Point, radius: f64) -> Option<f64> {
+    let (fx, fy) = (a.0 - center.x as f64, a.1 - center.y as f64);
+    let c = fx * fx + fy * fy - radius * radius;
+    if c <= 0.0 {
+        return Some(0.0);
+    }
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let a = dx * dx + dy * dy;
+    let b = 2.0 * (fx * dx + fy * dy);
+    let disc = b * b - 4.0 * a * c;
+    if a == 0.0 || disc < 0.0 {
+        return None;
+    }
+    let s = (-b - disc.sqrt()) / (2.0 * a);
+    (0.0..=1.0).contains(&s).then_some(s)
+}
+
+// Positions of the player during one turn and the moments (from 0.0 to 1.0) when they were
+// reached. The player moves along straight lines between them.
+pub(crate) struct PlayerPath {
+    pub(crate) points: Vec<(f64, (f64, f64))>,
+}
+
+impl PlayerPath {
+    // Straight movement, which is reflected when the player bounces off the sides.
+    fn with_side_bounces(
+        start: Point,
+        movement: Point,
+        radius: i32,
+        width: i32,
+        height: i32,
+    ) -> Self {
+        let r = radius as f64;
+        let (max_x, max_y) = ((width - radius) as f64, (height - radius) as f64);
+        let pos_at = |t: f64| {
+            (
+                fold(start.x as f64 + t * movement.x as f64, r, max_x),
+                fold(start.y as f64 + t * movement.y as f64, r, max_y),
+            )
+        };
+        // Moments when the player bounces, the path is straight between them.
+        let mut times = vec![0.0, 1.0];
+        for (start, movement, max_pos) in
+            [(start.x, movement.x, max_x), (start.y, movement.y, max_y)]
+        {
+            if movement != 0 {
+                for bound in [r, max_pos] {
+                    let t = (bound - start as f64) / movement as f64;
+                    if t > 0.0 && t < 1.0 {
+                        times.push(t);
+                    }
+                }
+            }
+        }
+        times.sort_by(f64::total_cmp);
+        Self {
+            points: times.into_iter().map(|t| (t, pos_at(t))).collect(),
+        }
+    }
+}
+
+fn rounded_point(x: f64, y: f64) -> Point {
+    Point {
+        x: x.round() as i32,
+        y: y.round() as i32,
+    }
+}
+
+// Elastic collision of two players: if they overlap and move towards each other, the speed
+// components along the line between the centres are exchanged according to the masses.
+// After that players are pushed apart, the lighter one moves further.
+pub fn collide_players(a: &mut Player, b: &mut Player) {
+    let d = b.pos - a.pos;
+    let min_dist = (a.radius + b.radius) as f64;
+    if d.len2() >= min_dist * min_dist {
+        return;
+    }
+    let dist = d.len();
+    let (nx, ny) = if dist == 0.0 {
+        (1.0, 0.0)
+    } else {
+        (d.x as f64 / dist, d.y as f64 / dist)
+    };
+    let mass_a = (a.radius as f64) * (a.radius as f64);
+    let mass_b = (b.radius as f64) * (b.radius as f64);
+    let share_a = mass_b / (mass_a + mass_b);
+    let share_b = mass_a / (mass_a + mass_b);
+    let approach_speed = (a.speed.x - b.speed.x) as f64 * nx + (a.speed.y - b.speed.y) as f64 * ny;
+    if approach_speed > 0.0 {
+        let ka = 2.0 * share_a * approach_speed;
+        let kb = 2.0 * share_b * approach_speed;
+        a.speed = a.speed - rounded_point(nx * ka, ny * ka);
+        b.speed += rounded_point(nx * kb, ny * kb);
+    }
+    let overlap = min_dist - dist;
+    a.pos = a.pos - rounded_point(nx * overlap * share_a, ny * overlap * share_a);
+    b.pos += rounded_point(nx * overlap * share_b, ny * overlap * share_b);
 }
 
 impl GameState {
     pub fn next_turn(mut self) -> NextTurn {
+        let mut paths = vec![];
         for player in self.players.iter_mut() {
-            next_turn_player_state(player, self.width, self.height);
-        }
-        let mut ids: Vec<_> = (0..self.players.len()).collect();
-        let mut rng = thread_rng();
-        ids.shuffle(&mut rng);
-        for &id in ids.iter() {
-            for i in (0..self.items.len()).rev() {
-                if self.items[i].intersects(&self.players[id]) {
-                    self.players[id].score += 1;
-                    self.items.remove(i);
-                }
-            }
+            // Old games without obstacles keep exactly the same movement.
+            let path = if self.obstacles.is_empty() {
+                let start = player.pos;
+                let movement =
+                    next_turn_player_state(player, &self.config, self.width, self.height);
+                PlayerPath::with_side_bounces(
+                    start,
+                    movement,
+                    player.radius,
+                    self.width,
+                    self.height,
+                )
+            } else {
+                update_player_speed(player, &self.config);
+                obstacle::move_player(player, &self.obstacles, self.width, self.height)
+            };
+            paths.push(path);
+        }
+        if self.config.player_collisions {
+            self.collide_players();
         }
+        self.collect_items(&paths);
+        // Players could grow after collecting items.
+        for player in self.players.iter_mut() {
+            obstacle::push_out(player, &self.obstacles);
+        }
+        self.decay_items();
+        self.move_items();
         self.turn += 1;
         if self.turn == self.max_turns {
             return NextTurn::FinalResults(GameResults::new(self));
         }
+        self.remove_idle_players();
         self.update_size();
         self.add_more_items();
         NextTurn::GameState(self)
     }
 
+    // The earliest moment of the turn (from 0.0 to 1.0) when the player touches the item.
+    fn contact_time(&self, player: &Player, path: &PlayerPath, item: &Item) -> Option<f64> {
+        let touch_dist = (item.radius + player.radius) as f64;
+        for w in path.points.windows(2) {
+            let ((t0, a), (t1, b)) = (w[0], w[1]);
+            if let Some(s) = first_touch(a, b, item.pos, touch_dist) {
+                return Some(t0 + s * (t1 - t0));
+            }
+        }
+        // Players could be pushed onto the item after colliding with each other.
+        item.intersects(player).then_some(1.0)
+    }
+
+    // Points for the item, if several players touched it, they are distributed according
+    // to `config.item_tie_break`. `touches` are (contact time, player id).
+    fn item_points(&self, item: &Item, mut touches: Vec<(f64, usize)>) -> Vec<(usize, i64)> {
+        touches.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        let points = item.kind.points(&self.config);
+        match self.config.item_tie_break {
+            ItemTieBreak::EarliestContact => vec![(touches[0].1, points)],
+            ItemTieBreak::ClosestCentre => {
+                // Stable sort, so equal distances are still ordered by contact time.
+                touches.sort_by_key(|&(_, id)| self.players[id].pos.dist2(&item.pos));
+                vec![(touches[0].1, points)]
+            }
+            ItemTieBreak::Split => {
+                let cnt = touches.len() as i64;
+                touches
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &(_, id))| (id, points / cnt + i64::from((i as i64) < points % cnt)))
+                    .collect()
+            }
+        }
+    }
+
+    fn collect_items(&mut self, paths: &[PlayerPath]) {
+        let mut eaten = vec![];
+        let mut scores = vec![];
+        for item in self.items.iter() {
+            let touches: Vec<_> = self
+                .players
+                .iter()
+                .zip(paths.iter())
+                .enumerate()
+                .filter_map(|(id, (player, path))| {
+                    self.contact_time(player, path, item).map(|time| (time, id))
+                })
+                .collect();
+            eaten.push(!touches.is_empty());
+            if !touches.is_empty() {
+                for (id, points) in self.item_points(item, touches) {
+                    scores.push((id, points, item.kind));
+                }
+            }
+        }
+        // Effects of all items touched during the turn apply only after the turn.
+        for (id, points, kind) in scores {
+            self.players[id].score += points;
+            kind.apply_effect(&mut self.players[id], &self.config);
+        }
+        let mut eaten = eaten.into_iter();
+        self.items.retain(|_| !eaten.next().unwrap());
+    }
+
+    fn decay_items(&mut self) {
+        for item in self.items.iter_mut() {
+            if item.kind == ItemKind::Decaying {
+                item.radius -= self.config.decay_per_turn;
+            }
+        }
+        self.items.retain(|item| item.radius > 0);
+    }
+
+    // Items move after players collected them, so during the turn they stay in place.
+    fn move_items(&mut self) {
+        for item in self.items.iter_mut() {
+            if item.speed == Point::ZERO {
+                continue;
+            }
+            let old_pos = item.pos;
+            item.pos += item.speed;
+            clamp(
+                &mut item.pos.x,
+                &mut item.speed.x,
+                item.radius,
+                self.width - item.radius,
+            );
+            clamp(
+                &mut item.pos.y,
+                &mut item.speed.y,
+                item.radius,
+                self.height - item.radius,
+            );
+            // Items never get inside obstacles, they just turn back.
+            if self
+                .obstacles
+                .iter()
+                .any(|obstacle| obstacle.intersects(item.pos, item.radius))
+            {
+                item.pos = old_pos;
+                item.speed = Point::ZERO - item.speed;
+            }
+        }
+    }
+
+    // Pairs are resolved in the order of players, so the result is deterministic.
+    fn collide_players(&mut self) {
+        for j in 1..self.players.len() {
+            let (left, right) = self.players.split_at_mut(j);
+            for a in left.iter_mut() {
+                collide_players(a, &mut right[0]);
+            }
+        }
+        for player in self.players.iter_mut() {
+            player.pos.x = player
+                .pos
+                .x
+                .clamp(player.radius, self.width - player.radius);
+            player.pos.y = player
+                .pos
+                .y
+                .clamp(player.radius, self.height - player.radius);
+            obstacle::push_out(player, &self.obstacles);
+        }
+    }
+
+    fn remove_idle_players(&mut self) {
+        let max_idle_turns = self.config.max_idle_turns;
+        if max_idle_turns == 0 {
+            return;
+        }
+        for player in self.players.iter_mut() {
+            player.idle_turns += 1;
+        }
+        let (idle, active): (Vec<_>, Vec<_>) = std::mem::take(&mut self.players)
+            .into_iter()
+            .partition(|player| player.idle_turns > max_idle_turns);
+        self.players = active;
+        self.inactive_players.extend(idle);
+    }
+
+    // With `resize_step` the field first moves towards the target from the previous state,
+    // so bots see the new target at least one turn before the size starts to change.
     fn update_size(&mut self) {
         let scaling = self.scaling_coef().sqrt();
-        self.width = ((START_WIDTH as f64) * scaling).round() as i32;
-        self.height = ((START_HEIGHT as f64) * scaling).round() as i32;
+        let target_width = ((self.config.start_width as f64) * scaling).round() as i32;
+        let target_height = ((self.config.start_height as f64) * scaling).round() as i32;
+        let step = self.config.resize_step;
+        if step == 0 {
+            self.width = target_width;
+            self.height = target_height;
+        } else {
+            self.width += (self.target_width - self.width).clamp(-step, step);
+            self.height += (self.target_height - self.height).clamp(-step, step);
+        }
+        self.target_width = target_width;
+        self.target_height = target_height;
+        self.keep_inside_field();
+    }
+
+    // After the field shrinks, players are pushed back by the side as if they bounced off it,
+    // and items which are not fully inside anymore disappear (new ones appear by usual rules).
+    fn keep_inside_field(&mut self) {
+        for player in self.players.iter_mut() {
+            for (pos, speed, size) in [
+                (&mut player.pos.x, &mut player.speed.x, self.width),
+                (&mut player.pos.y, &mut player.speed.y, self.height),
+            ] {
+                if *pos > size - player.radius {
+                    *pos = size - player.radius;
+                    *speed = -speed.abs();
+                }
+                if *pos < player.radius {
+                    *pos = player.radius;
+                    *speed = speed.abs();
+                }
+            }
+            // The side could push the player into an obstacle.
+            obstacle::push_out(player, &self.obstacles);
+        }
+        let (width, height) = (self.width, self.height);
+        self.items
+            .retain(|item| item.pos.x + item.radius <= width && item.pos.y + item.radius <= height);
     }
 
     fn calc_max_items(&self) -> usize {
-        ((MAX_ITEMS as f64) * self.scaling_coef()).round() as usize
+        ((self.config.max_items as f64) * self.scaling_coef()).round() as usize
     }
 
     fn scaling_coef(&self) -> f64 {
-        if self.players.len() < START_MAX_PLAYERS {
+        if self.players.len() < self.config.start_max_players {
             return 1.0;
         }
-        (self.players.len() as f64) / (START_MAX_PLAYERS as f64)
+        (self.players.len() as f64) / (self.config.start_max_players as f64)
     }
 
+    // If the field is too crowded, the remaining items appear on the next turns.
     fn add_more_items(&mut self) {
-        let mut rng = thread_rng();
-
         let max_items = self.calc_max_items();
-        while self.items.len() < max_items {
-            // TODO: make logic more interesting
-            let r = rng.gen_range(MIN_ITEM_R..MAX_ITEM_R);
+        let mut failed_attempts = 0;
+        while self.items.len() < max_items && failed_attempts < MAX_PLACEMENT_ATTEMPTS {
+            let r = self
+                .rng
+                .gen_range(self.config.min_item_r..self.config.max_item_r);
+            let Some(pos) = self.gen_free_position(r) else {
+                break;
+            };
+            let kind = if self.config.item_kinds {
+                self.gen_rand_item_kind()
+            } else {
+                ItemKind::Regular
+            };
+            let speed = if self.config.moving_items {
+                let max_speed = self.config.max_item_speed;
+                Point {
+                    x: self.rng.gen_range(-max_speed..=max_speed),
+                    y: self.rng.gen_range(-max_speed..=max_speed),
+                }
+            } else {
+                Point::ZERO
+            };
             let new_item = Item {
-                pos: self.gen_rand_position(r),
+                pos,
                 radius: r,
+                kind,
+                speed,
             };
             let mut ok = true;
             for existing in self.items.iter() {
@@ -195,71 +647,148 @@ impl GameState {
             }
             if ok {
                 self.items.push(new_item)
+            } else {
+                failed_attempts += 1;
+            }
+        }
+    }
+
+    fn gen_rand_item_kind(&mut self) -> ItemKind {
+        let total: u32 = ItemKind::ALL.iter().map(|kind| kind.weight()).sum();
+        let mut value = self.rng.gen_range(0..total);
+        for kind in ItemKind::ALL {
+            if value < kind.weight() {
+                return kind;
             }
+            value -= kind.weight();
         }
+        unreachable!()
     }
 
-    fn gen_rand_position(&self, radius: i32) -> Point {
-        let mut rng = thread_rng();
-        let x = rng.gen_range(radius..self.width - radius);
-        let y = rng.gen_range(radius..self.height - radius);
+    fn gen_rand_position(&mut self, radius: i32) -> Point {
+        let x = self.rng.gen_range(radius..self.width - radius);
+        let y = self.rng.gen_range(radius..self.height - radius);
         Point { x, y }
     }
 
-    pub fn new(game_id: &str) -> Self {
+    // Random position outside of all obstacles, `None` if obstacles cover almost the whole field.
+    fn gen_free_position(&mut self, radius: i32) -> Option<Point> {
+        for _ in 0..MAX_PLACEMENT_ATTEMPTS {
+            let pos = self.gen_rand_position(radius);
+            if !self
+                .obstacles
+                .iter()
+                .any(|obstacle| obstacle.intersects(pos, radius))
+            {
+                return Some(pos);
+            }
+        }
+        None
+    }
+
+    // Players have to join anyway, so the last resort is to push them out of an obstacle.
+    fn place_player(&mut self, player: &mut Player) {
+        player.pos = match self.gen_free_position(player.radius) {
+            Some(pos) => pos,
+            None => self.gen_rand_position(player.radius),
+        };
+        obstacle::push_out(player, &self.obstacles);
+    }
+
+    pub fn new(game_id: &str, seed: u64, config: GameConfig) -> Self {
         let mut res = Self {
-            width: START_WIDTH,
-            height: START_HEIGHT,
+            width: config.start_width,
+            height: config.start_height,
+            target_width: config.start_width,
+            target_height: config.start_height,
             turn: 0,
-            max_turns: MAX_TURNS,
+            max_turns: config.max_turns,
             players: vec![],
             items: vec![],
+            obstacles: vec![],
+            inactive_players: vec![],
             game_id: game_id.to_owned(),
+            seed,
+            rng: Box::new(ChaCha8Rng::seed_from_u64(seed)),
+            config: Box::new(config),
         };
+        res.obstacles = obstacle::gen_obstacles(&mut res.rng, &res.config);
         res.add_more_items();
         res
     }
 
-    pub fn to_string(&self) -> String {
+    pub fn from_string(s: &str) -> anyhow::Result<Self> {
+        Self::from_text(s, TextFormat::default())
+    }
+
+    pub fn from_text(s: &str, format: TextFormat) -> anyhow::Result<Self> {
+        Self::read(&mut TokenReader::new(s), format)
+    }
+
+    pub fn to_text(&self, format: TextFormat) -> String {
         let mut res = String::new();
-        res += &format!(
-            "TURN {turn} {max_turns} {width} {height} {game_id}\n",
-            turn = self.turn,
-            max_turns = self.max_turns,
-            width = self.width,
-            height = self.height,
-            game_id = self.game_id,
-        );
-        res += &format!("{}\n", self.players.len());
-        for player in self.players.iter() {
-            res += &format!(
-                "{name} {score} {x} {y} {r} {vx} {vy} {target_x} {target_y}\n",
-                name = player.name,
-                score = player.score,
-                x = player.pos.x,
-                y = player.pos.y,
-                r = player.radius,
-                vx = player.speed.x,
-                vy = player.speed.y,
-                target_x = player.target.x,
-                target_y = player.target.y,
-            );
-        }
-        res += &format!("{}\n", self.items.len());
-        for item in self.items.iter() {
-            res += &format!(
-                "{x} {y} {r}\n",
-                x = item.pos.x,
-                y = item.pos.y,
-                r = item.radius
-            );
-        }
-        res += "END_STATE\n";
+        self.write_text(&mut res, format)
+            .expect("Writing to String never fails");
         res
     }
 
-    pub fn from_string(s: &str) -> anyhow::Result<Self> {
-        let mut tokens = TokenReader::new(s);
+    // One line, so it could be sent the same way as other messages.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("GameState is always serializable")
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    pub fn to_checkpoint(&self) -> String {
+        let checkpoint = Checkpoint {
+            state: self.clone(),
+            seed: self.seed,
+            rng: (*self.rng).clone(),
+            config: (*self.config).clone(),
+            inactive_players: self.inactive_players.clone(),
+        };
+        serde_json::to_string(&checkpoint).expect("Checkpoint is always serializable")
+    }
+
+    pub fn from_checkpoint(s: &str) -> anyhow::Result<Self> {
+        let checkpoint: Checkpoint = serde_json::from_str(s)?;
+        let mut state = checkpoint.state;
+        state.seed = checkpoint.seed;
+        state.rng = Box::new(checkpoint.rng);
+        state.config = Box::new(checkpoint.config);
+        state.inactive_players = checkpoint.inactive_players;
+        Ok(state)
+    }
+
+    // Empty state used while parsing. It can't be simulated further, as the seed is unknown.
+    pub(crate) fn without_players(
+        game_id: String,
+        turn: usize,
+        max_turns: usize,
+        width: i32,
+        height: i32,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            target_width: width,
+            target_height: height,
+            turn,
+            max_turns,
+            players: vec![],
+            items: vec![],
+            obstacles: vec![],
+            inactive_players: vec![],
+            game_id,
+            seed: 0,
+            rng: default_rng(),
+            config: Box::default(),
+        }
+    }
+
+    pub(crate) fn read(tokens: &mut TokenReader, format: TextFormat) -> anyhow::Result<Self> {
         let cmd_word: String = tokens.next("TURN")?;
         if cmd_word != "TURN" {
             bail!("Expected TURN, got {}", cmd_word);
@@ -269,15 +798,11 @@ impl GameState {
         let width = tokens.next("width")?;
         let height = tokens.next("height")?;
         let game_id = tokens.next("game_id")?;
-        let mut res = Self {
-            width,
-            height,
-            turn,
-            max_turns,
-            players: vec![],
-            items: vec![],
-            game_id,
-        };
+        let mut res = Self::without_players(game_id, turn, max_turns, width, height);
+        if format.target_size {
+            res.target_width = tokens.next("target_width")?;
+            res.target_height = tokens.next("target_height")?;
+        }
         let num_players = tokens.next("num_players")?;
         for _ in 0..num_players {
             let name = tokens.next("player name")?;
@@ -289,6 +814,16 @@ impl GameState {
             let vy = tokens.next("player vy")?;
             let target_x = tokens.next("player target_x")?;
             let target_y = tokens.next("player target_y")?;
+            let boost_turns = if format.item_kinds {
+                tokens.next("player boost_turns")?
+            } else {
+                0
+            };
+            let idle_turns = if format.idle_turns {
+                tokens.next("player idle_turns")?
+            } else {
+                0
+            };
             res.players.push(Player {
                 name,
                 score,
@@ -299,6 +834,8 @@ impl GameState {
                     x: target_x,
                     y: target_y,
                 },
+                boost_turns,
+                idle_turns,
             });
         }
         let num_items = tokens.next("num items")?;
@@ -306,11 +843,32 @@ impl GameState {
             let x = tokens.next("item x")?;
             let y = tokens.next("item y")?;
             let r = tokens.next("item r")?;
+            let kind = if format.item_kinds {
+                tokens.next::<String>("item kind")?.parse()?
+            } else {
+                ItemKind::Regular
+            };
+            let speed = if format.item_speed {
+                Point {
+                    x: tokens.next("item vx")?,
+                    y: tokens.next("item vy")?,
+                }
+            } else {
+                Point::ZERO
+            };
             res.items.push(Item {
                 pos: Point { x, y },
                 radius: r,
+                kind,
+                speed,
             });
         }
+        if format.obstacles {
+            let num_obstacles = tokens.next("num obstacles")?;
+            for _ in 0..num_obstacles {
+                res.obstacles.push(Obstacle::read(tokens)?);
+            }
+        }
         let end_state: String = tokens.next("END_STATE")?;
         if end_state != "END_STATE" {
             bail!("Expected END_STATE, got {}", end_state);
@@ -319,12 +877,9 @@ impl GameState {
     }
 
     fn find_player_idx(&self, player_name: &str) -> Option<usize> {
-        for i in 0..self.players.len() {
-            if self.players[i].name == player_name {
-                return Some(i);
-            }
-        }
-        None
+        self.players
+            .iter()
+            .position(|player| player.name == player_name)
     }
 
     pub fn make_player_first(&mut self, player_name: &str) -> bool {
@@ -336,6 +891,16 @@ impl GameState {
         }
     }
 
+    // The player leaves the game, e.g. after disconnecting. Returns false if there is no such player.
+    pub fn remove_player(&mut self, player_name: &str) -> bool {
+        let Some(idx) = self.find_player_idx(player_name) else {
+            return false;
+        };
+        let player = self.players.remove(idx);
+        self.inactive_players.push(player);
+        true
+    }
+
     pub fn apply_move(&mut self, mut player_move: PlayerMove) {
         // TODO: validate move
         const MAX_C: u32 = u32::MAX / 10;
@@ -347,19 +912,104 @@ impl GameState {
         }
         if let Some(idx) = self.find_player_idx(&player_move.name) {
             self.players[idx].target = player_move.target;
+            self.players[idx].idle_turns = 0;
+        } else if let Some(idx) = self
+            .inactive_players
+            .iter()
+            .position(|player| player.name == player_move.name)
+        {
+            // The field could change while the player was away, so it is placed as a new one.
+            let mut player = self.inactive_players.remove(idx);
+            player.speed = Point::ZERO;
+            player.target = player_move.target;
+            player.idle_turns = 0;
+            self.place_player(&mut player);
+            self.players.push(player);
         } else {
-            let radius = PLAYER_RADIUS;
-            let pos = self.gen_rand_position(radius);
-            self.players.push(Player {
+            let radius = self.config.player_radius;
+            let mut player = Player {
                 name: player_move.name,
-                pos,
+                pos: Point::ZERO,
                 speed: Point::ZERO,
                 target: player_move.target,
                 score: 0,
                 radius,
-            });
+                boost_turns: 0,
+                idle_turns: 0,
+            };
+            self.place_player(&mut player);
+            self.players.push(player);
         }
     }
+
+    fn write_text(&self, f: &mut impl fmt::Write, format: TextFormat) -> fmt::Result {
+        writeln!(
+            f,
+            "TURN {turn} {max_turns} {width} {height} {game_id}",
+            turn = self.turn,
+            max_turns = self.max_turns,
+            width = self.width,
+            height = self.height,
+            game_id = self.game_id,
+        )?;
+        if format.target_size {
+            writeln!(f, "{} {}", self.target_width, self.target_height)?;
+        }
+        writeln!(f, "{}", self.players.len())?;
+        for player in self.players.iter() {
+            write!(
+                f,
+                "{name} {score} {x} {y} {r} {vx} {vy} {target_x} {target_y}",
+                name = player.name,
+                score = player.score,
+                x = player.pos.x,
+                y = player.pos.y,
+                r = player.radius,
+                vx = player.speed.x,
+                vy = player.speed.y,
+                target_x = player.target.x,
+                target_y = player.target.y,
+            )?;
+            if format.item_kinds {
+                write!(f, " {}", player.boost_turns)?;
+            }
+            if format.idle_turns {
+                write!(f, " {}", player.idle_turns)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "{}", self.items.len())?;
+        for item in self.items.iter() {
+            write!(
+                f,
+                "{x} {y} {r}",
+                x = item.pos.x,
+                y = item.pos.y,
+                r = item.radius
+            )?;
+            if format.item_kinds {
+                write!(f, " {}", item.kind)?;
+            }
+            if format.item_speed {
+                write!(f, " {} {}", item.speed.x, item.speed.y)?;
+            }
+            writeln!(f)?;
+        }
+        if format.obstacles {
+            writeln!(f, "{}", self.obstacles.len())?;
+            for obstacle in self.obstacles.iter() {
+                writeln!(f, "{obstacle}")?;
+            }
+        }
+        writeln!(f, "END_STATE")
+    }
+}
+
+// Format sent to players, see `TextFormat` for optional parts.
+impl fmt::Display for GameState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_text(f, TextFormat::default())
+    }
 }
 
 #[test]
@@ -371,8 +1021,10 @@ fn next_turn_state() {
         target: Point { x: 150, y: 200 }, // sent by `GO 150 200` command
         score: 0,
         radius: 1,
+        boost_turns: 0,
+        idle_turns: 0,
     };
-    next_turn_player_state(&mut player, 1000, 1000);
+    next_turn_player_state(&mut player, &GameConfig::default(), 1000, 1000);
     // acceleration direction is (150, 200) - (100, 100) = (50, 100)
     // the length of vector (50, 100) is sqrt(50^2 + 100^2) = 111.8, which is bigger than MAX_ACC=20.0, so real acceleration is:
     // (50, 100) * 20.0 / 111.8 = (8.9, 17.8)
@@ -382,3 +1034,398 @@ fn next_turn_state() {
     // new position is (100, 100) + (19, 18) = (119, 118)
     assert_eq!(player.pos, Point { x: 119, y: 118 });
 }
+
+#[test]
+fn same_seed_same_game() {
+    let play = |seed: u64| {
+        let mut state = GameState::new("game", seed, GameConfig::default());
+        let mut log = vec![];
+        for turn in 0..50 {
+            log.push(state.to_string());
+            for (i, name) in ["alice", "bob", "carol"].iter().enumerate() {
+                state.apply_move(PlayerMove {
+                    name: name.to_string(),
+                    target: Point {
+                        x: (turn * 37 + i * 500) as i32,
+                        y: (turn * 11 + i * 300) as i32,
+                    },
+                });
+            }
+            state = match state.next_turn() {
+                NextTurn::GameState(state) => state,
+                NextTurn::FinalResults(_) => unreachable!(),
+            };
+        }
+        log
+    };
+    assert_eq!(play(42), play(42));
+    assert_ne!(play(42), play(43));
+}
+
+#[test]
+fn json_round_trip() {
+    let text = "TURN 3 600 2000 1500 game-1
+2
+alice 5 100 200 20 -3 4 150 250
+bob 0 1000 700 20 0 0 0 0
+1
+300 400 25
+END_STATE
+";
+    let state = GameState::from_string(text).unwrap();
+    let json = state.to_json();
+    assert!(!json.contains('\n'));
+    assert_eq!(GameState::from_json(&json).unwrap().to_string(), text);
+
+    let results = GameResults::new(state);
+    let json = results.to_json();
+    let parsed = GameResults::from_json(&json).unwrap();
+    assert_eq!(parsed.game_id, "game-1");
+    assert_eq!(parsed.players[0].name, "alice");
+    assert_eq!(parsed.to_json(), json);
+}
+
+#[test]
+fn players_collide() {
+    let mut a = Player {
+        name: "a".to_owned(),
+        pos: Point { x: 125, y: 500 },
+        speed: Point { x: 25, y: 0 },
+        target: Point::ZERO,
+        score: 0,
+        radius: 20,
+        boost_turns: 0,
+        idle_turns: 0,
+    };
+    let mut b = Player {
+        name: "b".to_owned(),
+        pos: Point { x: 145, y: 500 },
+        speed: Point { x: -25, y: 0 },
+        target: Point::ZERO,
+        score: 0,
+        radius: 20,
+        boost_turns: 0,
+        idle_turns: 0,
+    };
+    // Same masses, so speeds are exchanged, and both are pushed apart by half of the overlap.
+    collide_players(&mut a, &mut b);
+    assert_eq!(
+        (a.speed, b.speed),
+        (Point { x: -25, y: 0 }, Point { x: 25, y: 0 })
+    );
+    assert_eq!((a.pos.x, b.pos.x), (115, 155));
+
+    // The heavier player only slows down.
+    let mut heavy = Player {
+        radius: 40,
+        pos: Point { x: 100, y: 100 },
+        speed: Point { x: 30, y: 0 },
+        ..a.clone()
+    };
+    let mut light = Player {
+        pos: Point { x: 150, y: 100 },
+        speed: Point::ZERO,
+        ..b.clone()
+    };
+    collide_players(&mut heavy, &mut light);
+    assert_eq!((heavy.speed.x, light.speed.x), (18, 48));
+    assert!(heavy.pos.x < 100 && light.pos.x > 150);
+}
+
+#[test]
+fn fast_player_collects_item_on_the_way() {
+    let config = GameConfig {
+        max_items: 1,
+        ..Default::default()
+    };
+    let mut state = GameState::new("game", 1, config);
+    let player = |name: &str, x: i32, vx: i32| Player {
+        name: name.to_owned(),
+        pos: Point { x, y: 500 },
+        speed: Point { x: vx, y: 0 },
+        // No acceleration.
+        target: Point { x, y: 500 },
+        score: 0,
+        radius: 20,
+        boost_turns: 0,
+        idle_turns: 0,
+    };
+    // Both players jump over the item (checking only positions before and after the turn would
+    // miss it), but "far" is listed first and touches the item later.
+    state.players = vec![player("far", 95, 100), player("near", 200, -100)];
+    state.items = vec![Item {
+        pos: Point { x: 150, y: 500 },
+        radius: 20,
+        kind: ItemKind::Regular,
+        speed: Point::ZERO,
+    }];
+    let NextTurn::GameState(state) = state.next_turn() else {
+        unreachable!()
+    };
+    assert_eq!(state.players[0].score, 0);
+    assert_eq!(state.players[1].score, 1);
+}
+
+// "first" touches the item earlier, but "second" ends the turn closer to it.
+#[cfg(test)]
+fn two_players_reach_item(item_tie_break: ItemTieBreak, item_points: i64) -> (i64, i64) {
+    let config = GameConfig {
+        max_items: 1,
+        item_points,
+        item_tie_break,
+        ..Default::default()
+    };
+    let mut state = GameState::new("game", 1, config);
+    let player = |name: &str, pos: Point, speed: Point| Player {
+        name: name.to_owned(),
+        pos,
+        speed,
+        target: pos,
+        score: 0,
+        radius: 20,
+        boost_turns: 0,
+        idle_turns: 0,
+    };
+    state.players = vec![
+        // touches at x = 110, ends at distance 45
+        player("first", Point { x: 95, y: 500 }, Point { x: 100, y: 0 }),
+        // touches at the very end of the turn, at distance 40
+        player("second", Point { x: 150, y: 600 }, Point { x: 0, y: -60 }),
+    ];
+    state.items = vec![Item {
+        pos: Point { x: 150, y: 500 },
+        radius: 20,
+        kind: ItemKind::Regular,
+        speed: Point::ZERO,
+    }];
+    let NextTurn::GameState(state) = state.next_turn() else {
+        unreachable!()
+    };
+    (state.players[0].score, state.players[1].score)
+}
+
+#[test]
+fn tie_break_earliest_contact() {
+    assert_eq!(
+        two_players_reach_item(ItemTieBreak::EarliestContact, 1),
+        (1, 0)
+    );
+}
+
+#[test]
+fn tie_break_closest_centre() {
+    assert_eq!(
+        two_players_reach_item(ItemTieBreak::ClosestCentre, 1),
+        (0, 1)
+    );
+}
+
+#[test]
+fn tie_break_split() {
+    assert_eq!(two_players_reach_item(ItemTieBreak::Split, 6), (3, 3));
+    // The remaining point goes to the player who touched the item first.
+    assert_eq!(two_players_reach_item(ItemTieBreak::Split, 5), (3, 2));
+    assert_eq!(two_players_reach_item(ItemTieBreak::Split, 1), (1, 0));
+}
+
+#[test]
+fn item_kinds() {
+    let config = GameConfig {
+        max_items: 3,
+        item_kinds: true,
+        ..Default::default()
+    };
+    let mut state = GameState::new("game", 1, config.clone());
+    let item = |x: i32, radius: i32, kind: ItemKind| Item {
+        pos: Point { x, y: 500 },
+        radius,
+        kind,
+        speed: Point::ZERO,
+    };
+    state.players = vec![Player {
+        name: "player".to_owned(),
+        pos: Point { x: 100, y: 500 },
+        speed: Point::ZERO,
+        target: Point { x: 100, y: 500 },
+        score: 0,
+        radius: 20,
+        boost_turns: 0,
+        idle_turns: 0,
+    }];
+    state.items = vec![
+        item(110, 10, ItemKind::Gem),
+        item(130, 10, ItemKind::SpeedBoost),
+        item(1000, 1, ItemKind::Decaying),
+    ];
+    let format = TextFormat::for_config(&config);
+    let text = state.to_text(format);
+    assert!(text.contains("110 500 10 gem\n"));
+    assert_eq!(
+        GameState::from_text(&text, format).unwrap().to_text(format),
+        text
+    );
+
+    let NextTurn::GameState(state) = state.next_turn() else {
+        unreachable!()
+    };
+    let player = &state.players[0];
+    assert_eq!(player.score, config.gem_points + config.item_points);
+    assert_eq!(player.boost_turns, config.boost_turns);
+    // The decaying item disappears, all three are replaced with new ones.
+    assert!(state.items.iter().all(|item| item.pos.x != 1000));
+}
+
+#[test]
+fn gradual_resize() {
+    let config = GameConfig {
+        start_max_players: 1,
+        resize_step: 10,
+        ..Default::default()
+    };
+    let mut state = GameState::new("game", 1, config.clone());
+    let next = |state: GameState| match state.next_turn() {
+        NextTurn::GameState(state) => state,
+        NextTurn::FinalResults(_) => unreachable!(),
+    };
+    for name in ["alice", "bob"] {
+        state.apply_move(PlayerMove {
+            name: name.to_owned(),
+            target: Point::ZERO,
+        });
+    }
+    // The new size is announced first, and the field starts growing only on the next turn.
+    state = next(state);
+    assert!(state.target_width > config.start_width);
+    assert_eq!(state.width, config.start_width);
+    state = next(state);
+    assert_eq!(state.width, config.start_width + 10);
+    while state.width < state.target_width {
+        state = next(state);
+    }
+
+    // "bob" leaves, "alice" is right at the side which moves.
+    state.players.truncate(1);
+    state.players[0].pos.x = state.width - state.players[0].radius;
+    state.players[0].speed = Point { x: 5, y: 0 };
+    state.players[0].target = state.players[0].pos;
+    let max_width = state.width;
+    state = next(state);
+    assert_eq!(state.target_width, config.start_width);
+    for turn in 1..=(max_width - config.start_width) / 10 + 1 {
+        state = next(state);
+        assert_eq!(state.width, (max_width - 10 * turn).max(config.start_width));
+        let player = &state.players[0];
+        assert!(player.pos.x + player.radius <= state.width);
+        assert!(state
+            .items
+            .iter()
+            .all(|item| item.pos.x + item.radius <= state.width));
+    }
+}
+
+#[test]
+fn idle_players_leave() {
+    let config = GameConfig {
+        start_max_players: 1,
+        max_idle_turns: 3,
+        ..Default::default()
+    };
+    let mut state = GameState::new("game", 1, config);
+    let next = |state: GameState| match state.next_turn() {
+        NextTurn::GameState(state) => state,
+        NextTurn::FinalResults(_) => unreachable!(),
+    };
+    let go = |state: &mut GameState, name: &str| {
+        state.apply_move(PlayerMove {
+            name: name.to_owned(),
+            target: Point::ZERO,
+        })
+    };
+    go(&mut state, "alice");
+    go(&mut state, "bob");
+    state = next(state);
+    assert!(state.target_width > state.config.start_width);
+    // "bob" keeps playing, "alice" doesn't move anymore.
+    for _ in 0..3 {
+        go(&mut state, "bob");
+        state = next(state);
+    }
+    assert_eq!(state.players.len(), 1);
+    assert_eq!(state.inactive_players[0].name, "alice");
+    // Ghosts don't make the field bigger.
+    assert_eq!(state.target_width, state.config.start_width);
+
+    state.inactive_players[0].score = 5;
+    state.inactive_players[0].pos = Point { x: -100, y: 5000 };
+    assert!(state.remove_player("bob"));
+    assert!(state.players.is_empty());
+    // Players come back with their scores, but the field could be smaller than before.
+    go(&mut state, "alice");
+    assert_eq!(state.players[0].score, 5);
+    let alice = &state.players[0];
+    assert!(alice.pos.x >= alice.radius && alice.pos.x + alice.radius <= state.width);
+    assert!(alice.pos.y >= alice.radius && alice.pos.y + alice.radius <= state.height);
+    state.max_turns = state.turn + 1;
+    let NextTurn::FinalResults(results) = state.next_turn() else {
+        unreachable!()
+    };
+    assert_eq!(results.players.len(), 2);
+}
+
+#[test]
+fn resume_from_checkpoint() {
+    let config = GameConfig {
+        item_kinds: true,
+        moving_items: true,
+        obstacles: 3,
+        ..Default::default()
+    };
+    let mut state = GameState::new("game", 123, config);
+    let mut restored: Option<GameState> = None;
+    for turn in 0..40 {
+        if turn == 20 {
+            restored = Some(GameState::from_checkpoint(&state.to_checkpoint()).unwrap());
+        }
+        let mut states = vec![&mut state];
+        states.extend(restored.as_mut());
+        for state in states {
+            let target = state.items[turn % state.items.len()].pos;
+            state.apply_move(PlayerMove {
+                name: "alice".to_owned(),
+                target,
+            });
+            match state.clone().next_turn() {
+                NextTurn::GameState(next_state) => *state = next_state,
+                NextTurn::FinalResults(_) => unreachable!(),
+            }
+        }
+        if let Some(restored) = &restored {
+            assert_eq!(
+                restored.to_text(TextFormat::all()),
+                state.to_text(TextFormat::all())
+            );
+        }
+    }
+}
+
+#[test]
+fn no_free_place_left() {
+    let mut state = GameState::new("game", 1, GameConfig::default());
+    state.obstacles = vec![Obstacle::Rect {
+        min: Point::ZERO,
+        max: Point {
+            x: state.width,
+            y: state.height,
+        },
+    }];
+    state.items.clear();
+    state.apply_move(PlayerMove {
+        name: "alice".to_owned(),
+        target: Point::ZERO,
+    });
+    assert_eq!(state.players.len(), 1);
+    let NextTurn::GameState(state) = state.next_turn() else {
+        unreachable!()
+    };
+    assert!(state.items.is_empty());
+}
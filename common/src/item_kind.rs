@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::game_config::GameConfig;
+use crate::game_state::Player;
+
+// Only `Regular` items appear unless `item_kinds` rule is enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    #[default]
+    Regular,
+    // Worth `gem_points` instead of `item_points`.
+    Gem,
+    // Radius decreases by `decay_per_turn` every turn, the item disappears when it reaches zero.
+    Decaying,
+    // Max speed of the player becomes `boost_max_speed` for the next `boost_turns` turns.
+    SpeedBoost,
+    // Radius of the player decreases by `radius_change`, but not below `min_player_radius`.
+    Shrink,
+    // Radius of the player increases by `radius_change`, but not above `max_player_radius`.
+    Grow,
+}
+
+impl ItemKind {
+    pub const ALL: [ItemKind; 6] = [
+        ItemKind::Regular,
+        ItemKind::Gem,
+        ItemKind::Decaying,
+        ItemKind::SpeedBoost,
+        ItemKind::Shrink,
+        ItemKind::Grow,
+    ];
+
+    // How often new items of this kind appear, relative to other kinds.
+    pub fn weight(&self) -> u32 {
+        match self {
+            ItemKind::Regular => 10,
+            ItemKind::Gem => 2,
+            ItemKind::Decaying => 3,
+            ItemKind::SpeedBoost => 2,
+            ItemKind::Shrink => 1,
+            ItemKind::Grow => 2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ItemKind::Regular => "regular",
+            ItemKind::Gem => "gem",
+            ItemKind::Decaying => "decaying",
+            ItemKind::SpeedBoost => "speed_boost",
+            ItemKind::Shrink => "shrink",
+            ItemKind::Grow => "grow",
+        }
+    }
+
+    pub fn points(&self, config: &GameConfig) -> i64 {
+        match self {
+            ItemKind::Gem => config.gem_points,
+            _ => config.item_points,
+        }
+    }
+
+    pub fn apply_effect(&self, player: &mut Player, config: &GameConfig) {
+        match self {
+            ItemKind::Regular | ItemKind::Gem | ItemKind::Decaying => {}
+            ItemKind::SpeedBoost => player.boost_turns = config.boost_turns,
+            ItemKind::Shrink => {
+                player.radius = (player.radius - config.radius_change).max(config.min_player_radius)
+            }
+            ItemKind::Grow => {
+                player.radius = (player.radius + config.radius_change).min(config.max_player_radius)
+            }
+        }
+    }
+}
+
+impl FromStr for ItemKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match Self::ALL.iter().find(|kind| kind.name() == s) {
+            Some(kind) => Ok(*kind),
+            None => bail!("Unknown item kind '{s}'"),
+        }
+    }
+}
+
+impl fmt::Display for ItemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -1,4 +1,11 @@
+pub mod binary_codec;
 pub mod consts;
+pub mod game_config;
 pub mod game_state;
+pub mod item_kind;
+pub mod obstacle;
 pub mod player_move;
 pub mod point;
+pub mod protocol;
+pub mod replay;
+pub mod state_delta;
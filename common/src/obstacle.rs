@@ -0,0 +1,393 @@
+// Static obstacles, generated at the start of the game and never changed after that.
+// Players bounce off them the same way as off the sides of the field: the speed is reflected
+// relative to the surface at the point of contact. Items and new players never appear inside them.
+
+use std::fmt;
+
+use anyhow::bail;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::game_config::GameConfig;
+use crate::game_state::{first_touch, Player, PlayerPath, TokenReader};
+use crate::point::Point;
+
+// A player stuck between several obstacles could bounce forever, after that many bounces
+// it just stops until the next turn.
+const MAX_BOUNCES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Obstacle {
+    Circle { center: Point, radius: i32 },
+    // Axis-aligned rectangle, `min` and `max` are its opposite corners.
+    Rect { min: Point, max: Point },
+}
+
+type Vec2 = (f64, f64);
+
+fn to_vec2(p: Point) -> Vec2 {
+    (p.x as f64, p.y as f64)
+}
+
+fn to_point(v: Vec2) -> Point {
+    Point {
+        x: v.0.round() as i32,
+        y: v.1.round() as i32,
+    }
+}
+
+fn lerp(a: Vec2, b: Vec2, s: f64) -> Vec2 {
+    (a.0 + s * (b.0 - a.0), a.1 + s * (b.1 - a.1))
+}
+
+fn dot(a: Vec2, b: Vec2) -> f64 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn reflect(v: Vec2, normal: Vec2) -> Vec2 {
+    let k = 2.0 * dot(v, normal);
+    (v.0 - k * normal.0, v.1 - k * normal.1)
+}
+
+// The smallest `s` from [0, 1] such that `a + s * (b - a)` is inside the rectangle.
+fn segment_enters_rect(a: Vec2, b: Vec2, min: Vec2, max: Vec2) -> Option<f64> {
+    let (mut lo, mut hi) = (0.0f64, 1.0f64);
+    for (start, end, min, max) in [(a.0, b.0, min.0, max.0), (a.1, b.1, min.1, max.1)] {
+        let d = end - start;
+        if d == 0.0 {
+            if start < min || start > max {
+                return None;
+            }
+        } else {
+            let (t1, t2) = ((min - start) / d, (max - start) / d);
+            lo = lo.max(t1.min(t2));
+            hi = hi.min(t1.max(t2));
+        }
+    }
+    (lo <= hi).then_some(lo)
+}
+
+impl Obstacle {
+    // Signed distance from `p` to the border of the obstacle (negative inside), and the outward
+    // normal of the border at the closest point.
+    fn distance(&self, p: Vec2) -> (f64, Vec2) {
+        match *self {
+            Obstacle::Circle { center, radius } => {
+                let (dx, dy) = (p.0 - center.x as f64, p.1 - center.y as f64);
+                let len = (dx * dx + dy * dy).sqrt();
+                let normal = if len == 0.0 {
+                    (1.0, 0.0)
+                } else {
+                    (dx / len, dy / len)
+                };
+                (len - radius as f64, normal)
+            }
+            Obstacle::Rect { min, max } => {
+                let closest = (
+                    p.0.clamp(min.x as f64, max.x as f64),
+                    p.1.clamp(min.y as f64, max.y as f64),
+                );
+                let (dx, dy) = (p.0 - closest.0, p.1 - closest.1);
+                let len = (dx * dx + dy * dy).sqrt();
+                if len > 0.0 {
+                    return (len, (dx / len, dy / len));
+                }
+                // Inside, the closest side wins.
+                let sides = [
+                    (p.0 - min.x as f64, (-1.0, 0.0)),
+                    (max.x as f64 - p.0, (1.0, 0.0)),
+                    (p.1 - min.y as f64, (0.0, -1.0)),
+                    (max.y as f64 - p.1, (0.0, 1.0)),
+                ];
+                let (dist, normal) = sides
+                    .into_iter()
+                    .min_by(|a, b| a.0.total_cmp(&b.0))
+                    .unwrap();
+                (-dist, normal)
+            }
+        }
+    }
+
+    pub fn intersects(&self, center: Point, radius: i32) -> bool {
+        self.distance(to_vec2(center)).0 < radius as f64
+    }
+
+    // The smallest `s` from [0, 1] such that a circle of `radius` at `a + s * (b - a)` touches the obstacle.
+    fn first_touch(&self, a: Vec2, b: Vec2, radius: f64) -> Option<f64> {
+        match *self {
+            Obstacle::Circle { center, radius: r } => first_touch(a, b, center, r as f64 + radius),
+            Obstacle::Rect { min, max } => {
+                // The rectangle extended by `radius` to all sides, with rounded corners.
+                let (x1, y1, x2, y2) = (min.x as f64, min.y as f64, max.x as f64, max.y as f64);
+                let sides = [
+                    segment_enters_rect(a, b, (x1 - radius, y1), (x2 + radius, y2)),
+                    segment_enters_rect(a, b, (x1, y1 - radius), (x2, y2 + radius)),
+                ];
+                let corners = [
+                    (min.x, min.y),
+                    (min.x, max.y),
+                    (max.x, min.y),
+                    (max.x, max.y),
+                ]
+                .map(|(x, y)| first_touch(a, b, Point { x, y }, radius));
+                sides
+                    .into_iter()
+                    .chain(corners)
+                    .flatten()
+                    .min_by(f64::total_cmp)
+            }
+        }
+    }
+
+    // Bounding box extended by `margin` to all sides.
+    fn bounding_box(&self, margin: i32) -> (Point, Point) {
+        let (min, max) = match *self {
+            Obstacle::Circle { center, radius } => (
+                Point {
+                    x: center.x - radius,
+                    y: center.y - radius,
+                },
+                Point {
+                    x: center.x + radius,
+                    y: center.y + radius,
+                },
+            ),
+            Obstacle::Rect { min, max } => (min, max),
+        };
+        let margin = Point {
+            x: margin,
+            y: margin,
+        };
+        (min - margin, max + margin)
+    }
+
+    pub(crate) fn read(tokens: &mut TokenReader) -> anyhow::Result<Self> {
+        let shape: String = tokens.next("obstacle shape")?;
+        match shape.as_str() {
+            "circle" => Ok(Obstacle::Circle {
+                center: Point {
+                    x: tokens.next("circle x")?,
+                    y: tokens.next("circle y")?,
+                },
+                radius: tokens.next("circle r")?,
+            }),
+            "rect" => Ok(Obstacle::Rect {
+                min: Point {
+                    x: tokens.next("rect x1")?,
+                    y: tokens.next("rect y1")?,
+                },
+                max: Point {
+                    x: tokens.next("rect x2")?,
+                    y: tokens.next("rect y2")?,
+                },
+            }),
+            _ => bail!("Expected circle or rect, got {shape}"),
+        }
+    }
+}
+
+// `circle [X] [Y] [R]` or `rect [X1] [Y1] [X2] [Y2]`.
+impl fmt::Display for Obstacle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obstacle::Circle { center, radius } => {
+                write!(f, "circle {} {} {}", center.x, center.y, radius)
+            }
+            Obstacle::Rect { min, max } => {
+                write!(f, "rect {} {} {} {}", min.x, min.y, max.x, max.y)
+            }
+        }
+    }
+}
+
+// Obstacles are placed far enough from each other and from the sides of the field,
+// so even the biggest player could fly between them.
+pub(crate) fn gen_obstacles(rng: &mut impl Rng, config: &GameConfig) -> Vec<Obstacle> {
+    let gap = 2 * config.max_player_radius + 1;
+    let (min_size, max_size) = (config.min_obstacle_size, config.max_obstacle_size);
+    let mut res: Vec<Obstacle> = vec![];
+    for _ in 0..config.obstacles {
+        // Give up on this obstacle if the field is too crowded.
+        for _ in 0..100 {
+            let size = Point {
+                x: rng.gen_range(min_size..=max_size),
+                y: rng.gen_range(min_size..=max_size),
+            };
+            let min = Point {
+                x: rng.gen_range(gap..=config.start_width - gap - size.x),
+                y: rng.gen_range(gap..=config.start_height - gap - size.y),
+            };
+            let obstacle = if rng.gen_bool(0.5) {
+                let radius = size.x.min(size.y) / 2;
+                Obstacle::Circle {
+                    center: Point {
+                        x: min.x + radius,
+                        y: min.y + radius,
+                    },
+                    radius,
+                }
+            } else {
+                Obstacle::Rect {
+                    min,
+                    max: min + size,
+                }
+            };
+            let (min, max) = obstacle.bounding_box(gap);
+            let overlaps = res.iter().any(|other| {
+                let (other_min, other_max) = other.bounding_box(0);
+                min.x <= other_max.x
+                    && other_min.x <= max.x
+                    && min.y <= other_max.y
+                    && other_min.y <= max.y
+            });
+            if !overlaps {
+                res.push(obstacle);
+                break;
+            }
+        }
+    }
+    res
+}
+
+// Moves the player by its speed, bouncing off the obstacles and the sides of the field.
+pub(crate) fn move_player(
+    player: &mut Player,
+    obstacles: &[Obstacle],
+    width: i32,
+    height: i32,
+) -> PlayerPath {
+    let r = player.radius as f64;
+    let (max_x, max_y) = (
+        (width - player.radius) as f64,
+        (height - player.radius) as f64,
+    );
+    let mut pos = to_vec2(player.pos);
+    let mut speed = to_vec2(player.speed);
+    // Part of the movement which is still left in this turn.
+    let mut movement = speed;
+    let mut time = 0.0;
+    let mut points = vec![(0.0, pos)];
+    for bounce in 0..=MAX_BOUNCES {
+        let end = (pos.0 + movement.0, pos.1 + movement.1);
+        // (s, normal) for all surfaces the player hits.
+        let mut hits = vec![];
+        for (start, end, movement, max_pos, axis) in [
+            (pos.0, end.0, movement.0, max_x, (1.0, 0.0)),
+            (pos.1, end.1, movement.1, max_y, (0.0, 1.0)),
+        ] {
+            if end < r && movement < 0.0 {
+                hits.push(((r - start) / movement, axis));
+            }
+            if end > max_pos && movement > 0.0 {
+                hits.push(((max_pos - start) / movement, (-axis.0, -axis.1)));
+            }
+        }
+        for obstacle in obstacles.iter() {
+            if let Some(s) = obstacle.first_touch(pos, end, r) {
+                let normal = obstacle.distance(lerp(pos, end, s)).1;
+                // Players which already touch the obstacle could fly away from it.
+                if dot(movement, normal) < 0.0 {
+                    hits.push((s, normal));
+                }
+            }
+        }
+        let Some((s, normal)) = hits.into_iter().min_by(|a, b| a.0.total_cmp(&b.0)) else {
+            pos = end;
+            break;
+        };
+        let s = s.clamp(0.0, 1.0);
+        pos = lerp(pos, end, s);
+        time += s * (1.0 - time);
+        points.push((time, pos));
+        if bounce == MAX_BOUNCES {
+            break;
+        }
+        movement = reflect(((1.0 - s) * movement.0, (1.0 - s) * movement.1), normal);
+        speed = reflect(speed, normal);
+    }
+    points.push((1.0, pos));
+    player.pos = to_point(pos);
+    player.pos.x = player.pos.x.clamp(player.radius, width - player.radius);
+    player.pos.y = player.pos.y.clamp(player.radius, height - player.radius);
+    player.speed = to_point(speed);
+    push_out(player, obstacles);
+    PlayerPath { points }
+}
+
+// Rounding and collisions between players could leave a player slightly inside an obstacle.
+pub(crate) fn push_out(player: &mut Player, obstacles: &[Obstacle]) {
+    for obstacle in obstacles.iter() {
+        let (dist, normal) = obstacle.distance(to_vec2(player.pos));
+        let overlap = player.radius as f64 - dist;
+        if overlap > 0.0 {
+            player.pos += to_point((normal.0 * (overlap + 1.0), normal.1 * (overlap + 1.0)));
+        }
+    }
+}
+
+#[test]
+fn bounce_off_obstacles() {
+    let player = |x: i32, vx: i32| Player {
+        name: "player".to_owned(),
+        pos: Point { x, y: 500 },
+        speed: Point { x: vx, y: 0 },
+        target: Point::ZERO,
+        score: 0,
+        radius: 20,
+        boost_turns: 0,
+        idle_turns: 0,
+    };
+    let wall = [Obstacle::Rect {
+        min: Point { x: 200, y: 0 },
+        max: Point { x: 210, y: 1000 },
+    }];
+    // Touches the wall at x = 180 and flies back, as it would from the side of the field.
+    let mut p = player(150, 50);
+    move_player(&mut p, &wall, 1000, 1000);
+    assert_eq!((p.pos.x, p.speed.x), (160, -50));
+    // The wall is thinner than the movement, but the player doesn't fly through it.
+    let mut p = player(170, 100);
+    move_player(&mut p, &wall, 1000, 1000);
+    assert_eq!((p.pos.x, p.speed.x), (90, -100));
+
+    // Hits the circle at an angle, the speed is reflected relative to the normal at the point of contact.
+    let circle = [Obstacle::Circle {
+        center: Point { x: 500, y: 500 },
+        radius: 50,
+    }];
+    let mut p = player(400, 0);
+    p.pos.y = 450;
+    p.speed = Point { x: 60, y: 60 };
+    move_player(&mut p, &circle, 1000, 1000);
+    assert!(p.speed.x < 0);
+    assert!(!circle[0].intersects(p.pos, p.radius));
+}
+
+#[test]
+fn obstacles_in_state() {
+    use crate::game_state::{GameState, TextFormat};
+
+    let config = GameConfig {
+        obstacles: 10,
+        ..Default::default()
+    };
+    let state = GameState::new("game", 1, config.clone());
+    assert!(!state.obstacles.is_empty());
+    for item in state.items.iter() {
+        assert!(state
+            .obstacles
+            .iter()
+            .all(|obstacle| !obstacle.intersects(item.pos, item.radius)));
+    }
+    let format = TextFormat::for_config(&config);
+    let text = state.to_text(format);
+    let parsed = GameState::from_text(&text, format).unwrap();
+    assert_eq!(parsed.obstacles, state.obstacles);
+    assert_eq!(
+        GameState::from_binary(&state.to_binary())
+            .unwrap()
+            .obstacles,
+        state.obstacles
+    );
+}
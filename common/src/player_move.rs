@@ -1,6 +1,6 @@
 use crate::point::Point;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PlayerMove {
     pub name: String,
     pub target: Point,
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::game_state::{GameState, TextFormat};
+
+// Version 1 is the original protocol: bare `HELLO`, `PLAY` or `WATCH`, and `TURN ... END_STATE` states.
+// The greeting is still the bare `HELLO`, so old clients keep working, and they get exactly the
+// version 1 format unless they enable capabilities.
+//
+// Newer clients could send `PROTOCOL` after `HELLO` and get `PROTOCOL [VERSION] [CAPABILITY]...`
+// with all capabilities supported by the server. Each of them is enabled with `ENABLE [CAPABILITY]`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    // After each `GO` reply with `MOVE_OK [TURN]` if the move was applied to the turn from the
+    // last sent state, or with `MOVE_LATE [TURN] [APPLIED_TURN]` if it arrived too late.
+    MoveReports,
+    // States are sent as one line of JSON (see `GameState::to_json`) instead of `TURN ... END_STATE`.
+    Json,
+    // States are sent as `BINARY [NUM_BYTES]` line followed by the bytes, see `binary_codec`.
+    Binary,
+    // Text states have `[BOOST_TURNS]` at the end of player lines and `[KIND]` at the end of item
+    // lines. Without it all items look regular.
+    ItemKinds,
+    // Text states have `[NUM_OBSTACLES]` and a line for each obstacle after the items.
+    Obstacles,
+    // Text states have `[VX] [VY]` at the end of item lines (after the kind if `ITEM_KINDS` is enabled).
+    ItemSpeed,
+    // Text states have `[TARGET_WIDTH] [TARGET_HEIGHT]` line after the `TURN` line.
+    Resize,
+    // Text states have `[IDLE_TURNS]` at the end of player lines (after `[BOOST_TURNS]` if `ITEM_KINDS` is enabled).
+    IdleTurns,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::MoveReports,
+        Capability::Json,
+        Capability::Binary,
+        Capability::ItemKinds,
+        Capability::Obstacles,
+        Capability::ItemSpeed,
+        Capability::Resize,
+        Capability::IdleTurns,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::MoveReports => "MOVE_REPORTS",
+            Capability::Json => "JSON",
+            Capability::Binary => "BINARY",
+            Capability::ItemKinds => "ITEM_KINDS",
+            Capability::Obstacles => "OBSTACLES",
+            Capability::ItemSpeed => "ITEM_SPEED",
+            Capability::Resize => "RESIZE",
+            Capability::IdleTurns => "IDLE_TURNS",
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match Self::ALL.iter().find(|cap| cap.name() == s) {
+            Some(cap) => Ok(*cap),
+            None => bail!("UNKNOWN feature '{s}'"),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Capabilities enabled by one client.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    enabled: Vec<Capability>,
+}
+
+impl Capabilities {
+    pub fn enable(&mut self, cap: Capability) {
+        if !self.has(cap) {
+            self.enabled.push(cap);
+        }
+    }
+
+    pub fn has(&self, cap: Capability) -> bool {
+        self.enabled.contains(&cap)
+    }
+}
+
+// Reply to the `PROTOCOL` command.
+pub fn protocol_line(supported: &[Capability]) -> String {
+    let mut res = format!("PROTOCOL {PROTOCOL_VERSION}");
+    for cap in supported.iter() {
+        res += &format!(" {cap}");
+    }
+    res
+}
+
+// State in the format requested by the client, ready to be sent as is.
+pub fn encode_state(state: &GameState, caps: &Capabilities) -> Vec<u8> {
+    if caps.has(Capability::Binary) {
+        let binary = state.to_binary();
+        let mut res = format!("BINARY {}\n", binary.len()).into_bytes();
+        res.extend(binary);
+        res
+    } else if caps.has(Capability::Json) {
+        format!("{}\n", state.to_json()).into_bytes()
+    } else {
+        let format = TextFormat {
+            item_kinds: caps.has(Capability::ItemKinds),
+            obstacles: caps.has(Capability::Obstacles),
+            item_speed: caps.has(Capability::ItemSpeed),
+            target_size: caps.has(Capability::Resize),
+            idle_turns: caps.has(Capability::IdleTurns),
+        };
+        format!("{}\n", state.to_text(format)).into_bytes()
+    }
+}
@@ -0,0 +1,302 @@
+// Replay file format, used for `data/games/[GAME_ID].txt` (`data/games/running/` until the game is finished):
+//
+// SEED [SEED]
+// CONFIG ... END_CONFIG                    <- rules of the game, default rules if missing
+// TURN [CUR_TURN] ... END_STATE            <- state before the turn, same format as sent to players,
+//                                             with all optional parts the config needs (see `TextFormat`)
+// MOVES [NUM_MOVES]
+// [PLAYER_NAME] [TARGET_X] [TARGET_Y]      <- in the order they were applied, the first move of a player joins the game
+// ... ([NUM_MOVES - 1] more lines)
+// LEFT [NUM_PLAYERS]                       <- only if some players left during the turn, applied after the moves
+// [PLAYER_NAME]
+// ... ([NUM_PLAYERS - 1] more lines)
+// ... (same for all other turns)
+// ENDED                                    <- only if the game was ended early by the admin, right after the state
+// RESULTS [NUM_PLAYERS]                    <- only if the game is finished
+// [PLAYER_NAME] [SCORE]
+// ... ([NUM_PLAYERS - 1] more lines)
+//
+// As `GameState` is deterministic for a fixed seed and config, the whole game could be simulated
+// again from the seed, config and moves, and compared with the stored states.
+
+use anyhow::bail;
+
+use crate::game_config::GameConfig;
+use crate::game_state::{GameResults, GameState, NextTurn, TextFormat, TokenReader};
+use crate::player_move::PlayerMove;
+use crate::point::Point;
+
+pub struct ReplayTurn {
+    pub state: GameState,
+    pub moves: Vec<PlayerMove>,
+    // Players who disconnected or sent `EXIT` during the turn.
+    pub left: Vec<String>,
+}
+
+pub struct Replay {
+    pub seed: u64,
+    pub config: GameConfig,
+    pub turns: Vec<ReplayTurn>,
+    // (name, score) sorted by score, `None` if the game was not finished.
+    pub results: Option<Vec<(String, i64)>>,
+    // The game was ended by the admin before `max_turns`, results are taken from the last state.
+    pub ended_early: bool,
+}
+
+pub fn header_to_string(seed: u64, config: &GameConfig) -> String {
+    format!("SEED {seed}\n{config}")
+}
+
+pub fn moves_to_string(moves: &[PlayerMove]) -> String {
+    let mut res = format!("MOVES {}\n", moves.len());
+    for player_move in moves.iter() {
+        res += &format!(
+            "{} {} {}\n",
+            player_move.name, player_move.target.x, player_move.target.y
+        );
+    }
+    res
+}
+
+pub fn left_to_string(left: &[String]) -> String {
+    if left.is_empty() {
+        return String::new();
+    }
+    let mut res = format!("LEFT {}\n", left.len());
+    for name in left.iter() {
+        res += &format!("{name}\n");
+    }
+    res
+}
+
+pub fn ended_to_string() -> String {
+    "ENDED\n".to_owned()
+}
+
+pub fn results_to_string(results: &GameResults) -> String {
+    let mut res = format!("RESULTS {}\n", results.players.len());
+    for player in results.players.iter() {
+        res += &format!("{} {}\n", player.name, player.score);
+    }
+    res
+}
+
+fn results_list(results: GameResults) -> Vec<(String, i64)> {
+    results
+        .players
+        .into_iter()
+        .map(|player| (player.name, player.score))
+        .collect()
+}
+
+impl Replay {
+    pub fn from_string(s: &str) -> anyhow::Result<Self> {
+        let mut tokens = TokenReader::new(s);
+        let seed_word: String = tokens.next("SEED")?;
+        if seed_word != "SEED" {
+            bail!("Expected SEED, got {}", seed_word);
+        }
+        let seed = tokens.next("seed")?;
+        // Replays written before rules became configurable don't have the config.
+        let config = if tokens.peek() == Some("CONFIG") {
+            GameConfig::read(&mut tokens)?
+        } else {
+            GameConfig::default()
+        };
+        let format = TextFormat::for_config(&config);
+        let mut res = Self {
+            seed,
+            config,
+            turns: vec![],
+            results: None,
+            ended_early: false,
+        };
+        // The file could be cut in the middle of the game, so everything is optional.
+        while let Some(word) = tokens.peek() {
+            if word == "TURN" {
+                res.turns.push(ReplayTurn {
+                    state: GameState::read(&mut tokens, format)?,
+                    moves: vec![],
+                    left: vec![],
+                });
+                continue;
+            }
+            let word: String = tokens.next("MOVES or RESULTS")?;
+            if word == "MOVES" {
+                let Some(turn) = res.turns.last_mut() else {
+                    bail!("MOVES before the first state");
+                };
+                let num_moves = tokens.next("num moves")?;
+                for _ in 0..num_moves {
+                    let name = tokens.next("move name")?;
+                    let x = tokens.next("move x")?;
+                    let y = tokens.next("move y")?;
+                    turn.moves.push(PlayerMove {
+                        name,
+                        target: Point { x, y },
+                    });
+                }
+            } else if word == "LEFT" {
+                let Some(turn) = res.turns.last_mut() else {
+                    bail!("LEFT before the first state");
+                };
+                let num_players = tokens.next("num left players")?;
+                for _ in 0..num_players {
+                    turn.left.push(tokens.next("left player name")?);
+                }
+            } else if word == "ENDED" {
+                res.ended_early = true;
+            } else if word == "RESULTS" {
+                let num_players = tokens.next("num players")?;
+                let mut results = vec![];
+                for _ in 0..num_players {
+                    let name = tokens.next("player name")?;
+                    let score = tokens.next("player score")?;
+                    results.push((name, score));
+                }
+                res.results = Some(results);
+            } else {
+                bail!("Expected TURN, MOVES, LEFT, ENDED or RESULTS, got {}", word);
+            }
+        }
+        Ok(res)
+    }
+
+    // Simulates the game from the seed and moves, and checks that all states are the same as stored.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let Some(first) = self.turns.first() else {
+            bail!("Replay doesn't contain any states");
+        };
+        let mut state = GameState::new(&first.state.game_id, self.seed, self.config.clone());
+        state.max_turns = first.state.max_turns;
+        let format = TextFormat::for_config(&self.config);
+        for (i, turn) in self.turns.iter().enumerate() {
+            if state.to_text(format) != turn.state.to_text(format) {
+                bail!(
+                    "Simulated state differs from the stored one on turn {}",
+                    turn.state.turn
+                );
+            }
+            if i + 1 == self.turns.len() && self.results.is_none() {
+                // The game was not finished, nothing else to check.
+                return Ok(());
+            }
+            if i + 1 == self.turns.len() && self.ended_early {
+                if Some(&results_list(GameResults::new(state))) != self.results.as_ref() {
+                    bail!("Simulated results differ from the stored ones");
+                }
+                return Ok(());
+            }
+            for player_move in turn.moves.iter() {
+                state.apply_move(player_move.clone());
+            }
+            for name in turn.left.iter() {
+                state.remove_player(name);
+            }
+            match state.next_turn() {
+                NextTurn::GameState(next_state) => state = next_state,
+                NextTurn::FinalResults(results) => {
+                    if i + 1 != self.turns.len() {
+                        bail!(
+                            "Game finished after turn {}, but replay has more states",
+                            turn.state.turn
+                        );
+                    }
+                    if Some(&results_list(results)) != self.results.as_ref() {
+                        bail!("Simulated results differ from the stored ones");
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        bail!("Replay has results, but the game was not finished");
+    }
+}
+
+#[test]
+fn simulated_replay_is_valid() {
+    let seed = 123;
+    let config = GameConfig {
+        max_turns: 30,
+        ..Default::default()
+    };
+    let mut log = header_to_string(seed, &config);
+    let mut state = GameState::new("game", seed, config);
+    loop {
+        log += &state.to_string();
+        // "bob" leaves for a while.
+        let names = if (10..20).contains(&state.turn) {
+            vec!["alice"]
+        } else {
+            vec!["alice", "bob"]
+        };
+        let moves: Vec<_> = names
+            .iter()
+            .map(|name| PlayerMove {
+                name: name.to_string(),
+                target: state.items[0].pos,
+            })
+            .collect();
+        let left = if state.turn == 10 {
+            vec!["bob".to_owned()]
+        } else {
+            vec![]
+        };
+        log += &moves_to_string(&moves);
+        log += &left_to_string(&left);
+        for player_move in moves {
+            state.apply_move(player_move);
+        }
+        for name in left {
+            state.remove_player(&name);
+        }
+        match state.next_turn() {
+            NextTurn::GameState(next_state) => state = next_state,
+            NextTurn::FinalResults(results) => {
+                log += &results_to_string(&results);
+                break;
+            }
+        }
+    }
+    let replay = Replay::from_string(&log).unwrap();
+    assert_eq!(replay.turns.len(), 30);
+    replay.verify().unwrap();
+
+    let tampered = log.replacen("MOVES 2\nalice", "MOVES 2\nbob", 1);
+    assert!(Replay::from_string(&tampered).unwrap().verify().is_err());
+    let tampered = log.replacen("LEFT 1\nbob\n", "", 1);
+    assert!(Replay::from_string(&tampered).unwrap().verify().is_err());
+}
+
+#[test]
+fn ended_early_replay_is_valid() {
+    let seed = 123;
+    let config = GameConfig::default();
+    let mut log = header_to_string(seed, &config);
+    let mut state = GameState::new("game", seed, config);
+    for _ in 0..5 {
+        log += &state.to_string();
+        let player_move = PlayerMove {
+            name: "alice".to_owned(),
+            target: state.items[0].pos,
+        };
+        let moves = vec![player_move];
+        log += &moves_to_string(&moves);
+        for player_move in moves {
+            state.apply_move(player_move);
+        }
+        state = match state.next_turn() {
+            NextTurn::GameState(next_state) => next_state,
+            NextTurn::FinalResults(_) => unreachable!(),
+        };
+    }
+    log += &state.to_string();
+    log += &ended_to_string();
+    log += &results_to_string(&GameResults::new(state));
+    let replay = Replay::from_string(&log).unwrap();
+    assert!(replay.ended_early);
+    replay.verify().unwrap();
+
+    let tampered = log.replacen("ENDED\n", "", 1);
+    assert!(Replay::from_string(&tampered).unwrap().verify().is_err());
+}
@@ -0,0 +1,322 @@
+// Spectators get the full state once (keyframe), and after that only the difference between
+// the previous state they got and the new one. Most of the items don't change between turns,
+// so deltas are much smaller than full states on big fields.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::game_state::{GameState, Item, Player};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDelta {
+    pub turn: usize,
+    pub width: i32,
+    pub height: i32,
+    #[serde(default)]
+    pub target_width: i32,
+    #[serde(default)]
+    pub target_height: i32,
+    // New players, and players whose position, speed, target or score changed.
+    pub players: Vec<Player>,
+    // Indices in the items of the previous state, in increasing order.
+    pub removed_items: Vec<usize>,
+    // Items which moved or changed: (index after removing `removed_items`, new item), in increasing order.
+    #[serde(default)]
+    pub updated_items: Vec<(usize, Item)>,
+    // Appended after removing `removed_items`.
+    pub added_items: Vec<Item>,
+}
+
+fn same_visible_state(a: &GameState, b: &GameState) -> bool {
+    a.game_id == b.game_id
+        && a.turn == b.turn
+        && a.max_turns == b.max_turns
+        && a.width == b.width
+        && a.height == b.height
+        && a.target_width == b.target_width
+        && a.target_height == b.target_height
+        && a.players == b.players
+        && a.items == b.items
+        && a.obstacles == b.obstacles
+}
+
+// Moving and decaying items change every turn, but they are still the same items.
+// A wrong guess only makes the delta bigger, as deltas are checked anyway.
+fn same_item(prev: &Item, next: &Item, turns: usize) -> bool {
+    prev.kind == next.kind && (next.pos - prev.pos).len() <= prev.speed.len() * turns as f64
+}
+
+impl StateDelta {
+    // `None` if `next` can't be described as a delta from `prev`, e.g. it is a new game.
+    pub fn new(prev: &GameState, next: &GameState) -> Option<Self> {
+        if prev.game_id != next.game_id || prev.max_turns != next.max_turns {
+            return None;
+        }
+        let players = next
+            .players
+            .iter()
+            .filter(|player| !prev.players.contains(player))
+            .cloned()
+            .collect();
+        // Eaten items are removed from the middle and new items are added to the end.
+        let turns = next.turn.saturating_sub(prev.turn);
+        let mut removed_items = vec![];
+        let mut updated_items = vec![];
+        let mut kept = 0;
+        for (i, item) in prev.items.iter().enumerate() {
+            match next.items.get(kept) {
+                Some(next_item) if next_item == item => kept += 1,
+                Some(next_item) if same_item(item, next_item, turns) => {
+                    updated_items.push((kept, next_item.clone()));
+                    kept += 1;
+                }
+                _ => removed_items.push(i),
+            }
+        }
+        let delta = Self {
+            turn: next.turn,
+            width: next.width,
+            height: next.height,
+            target_width: next.target_width,
+            target_height: next.target_height,
+            players,
+            removed_items,
+            updated_items,
+            added_items: next.items[kept..].to_vec(),
+        };
+        // Players are never reordered now, but it is cheap to make sure.
+        match delta.apply(prev) {
+            Ok(state) if same_visible_state(&state, next) => Some(delta),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, prev: &GameState) -> anyhow::Result<GameState> {
+        let mut state = prev.clone();
+        state.turn = self.turn;
+        state.width = self.width;
+        state.height = self.height;
+        state.target_width = self.target_width;
+        state.target_height = self.target_height;
+        for player in self.players.iter() {
+            match state.players.iter_mut().find(|p| p.name == player.name) {
+                Some(existing) => *existing = player.clone(),
+                None => state.players.push(player.clone()),
+            }
+        }
+        let mut removed = self.removed_items.iter().peekable();
+        let mut items = Vec::with_capacity(state.items.len());
+        for (i, item) in state.items.into_iter().enumerate() {
+            if removed.peek() == Some(&&i) {
+                removed.next();
+            } else {
+                items.push(item);
+            }
+        }
+        if removed.next().is_some() {
+            bail!("Delta removes items which don't exist");
+        }
+        for (idx, item) in self.updated_items.iter() {
+            match items.get_mut(*idx) {
+                Some(existing) => *existing = item.clone(),
+                None => bail!("Delta updates items which don't exist"),
+            }
+        }
+        items.extend(self.added_items.iter().cloned());
+        state.items = items;
+        Ok(state)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum SpectatorMessage {
+    Keyframe(GameState),
+    Delta(StateDelta),
+}
+
+impl SpectatorMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("SpectatorMessage is always serializable")
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+// Server side, shared by all spectators of a room. States of a room are identified by the game
+// and the turn, so spectators which got the same previous state share the delta to the latest
+// state, and it is computed (and checked) only once per turn.
+#[derive(Clone, Default)]
+pub struct DeltaCache {
+    inner: Arc<Mutex<CachedDeltas>>,
+}
+
+#[derive(Default)]
+struct CachedDeltas {
+    // (game_id, turn) of the state the deltas lead to.
+    next: Option<(String, usize)>,
+    // By (game_id, turn) of the previous state.
+    deltas: HashMap<(String, usize), Option<StateDelta>>,
+}
+
+impl DeltaCache {
+    fn delta(&self, prev: &GameState, next: &GameState) -> Option<StateDelta> {
+        let mut cached = self.inner.lock().unwrap();
+        let next_key = (next.game_id.clone(), next.turn);
+        if cached.next.as_ref() != Some(&next_key) {
+            cached.next = Some(next_key);
+            cached.deltas.clear();
+        }
+        cached
+            .deltas
+            .entry((prev.game_id.clone(), prev.turn))
+            .or_insert_with(|| StateDelta::new(prev, next))
+            .clone()
+    }
+}
+
+// Server side, one for each spectator.
+#[derive(Default)]
+pub struct SpectatorEncoder {
+    last_sent: Option<GameState>,
+    deltas: DeltaCache,
+}
+
+impl SpectatorEncoder {
+    pub fn new(deltas: DeltaCache) -> Self {
+        Self {
+            last_sent: None,
+            deltas,
+        }
+    }
+
+    pub fn encode(&mut self, state: &GameState) -> SpectatorMessage {
+        let delta = self
+            .last_sent
+            .as_ref()
+            .and_then(|prev| self.deltas.delta(prev, state));
+        self.last_sent = Some(state.clone());
+        match delta {
+            Some(delta) => SpectatorMessage::Delta(delta),
+            None => SpectatorMessage::Keyframe(state.clone()),
+        }
+    }
+}
+
+// Client side, restores full states from messages.
+#[derive(Default)]
+pub struct SpectatorDecoder {
+    last_received: Option<GameState>,
+}
+
+impl SpectatorDecoder {
+    pub fn decode(&mut self, message: SpectatorMessage) -> anyhow::Result<GameState> {
+        let state = match message {
+            SpectatorMessage::Keyframe(state) => state,
+            SpectatorMessage::Delta(delta) => match &self.last_received {
+                Some(prev) => delta.apply(prev)?,
+                None => bail!("Got delta before the first keyframe"),
+            },
+        };
+        self.last_received = Some(state.clone());
+        Ok(state)
+    }
+}
+
+#[test]
+fn deltas_restore_states() {
+    use crate::game_config::GameConfig;
+
+    let moving_items = GameConfig {
+        moving_items: true,
+        item_kinds: true,
+        ..Default::default()
+    };
+    for config in [GameConfig::default(), moving_items] {
+        deltas_restore_states_with(config);
+    }
+}
+
+#[test]
+fn spectators_share_deltas() {
+    use crate::game_config::GameConfig;
+    use crate::game_state::NextTurn;
+
+    let deltas = DeltaCache::default();
+    let mut encoders: Vec<_> = (0..3)
+        .map(|_| SpectatorEncoder::new(deltas.clone()))
+        .collect();
+    let mut state = GameState::new("game", 1, GameConfig::default());
+    for turn in 0..5 {
+        // The last spectator skips odd turns, so it needs a different delta.
+        let messages: Vec<_> = encoders
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| *i < 2 || turn % 2 == 0)
+            .map(|(_, encoder)| encoder.encode(&state).to_json())
+            .collect();
+        assert_eq!(messages[0], messages[1]);
+        if turn > 0 {
+            let cached = deltas.inner.lock().unwrap();
+            assert_eq!(cached.deltas.len(), messages.len() - 1);
+        }
+        state = match state.next_turn() {
+            NextTurn::GameState(state) => state,
+            NextTurn::FinalResults(_) => unreachable!(),
+        };
+    }
+}
+
+#[cfg(test)]
+fn deltas_restore_states_with(config: crate::game_config::GameConfig) {
+    use crate::game_config::GameConfig;
+    use crate::game_state::{NextTurn, TextFormat};
+    use crate::player_move::PlayerMove;
+
+    let mut encoder = SpectatorEncoder::default();
+    let mut decoder = SpectatorDecoder::default();
+    let mut state = GameState::new("game", 1, config);
+    for turn in 0..100 {
+        // Spectators don't get every state, so deltas could skip several turns.
+        if turn % 7 != 3 {
+            let message = encoder.encode(&state);
+            assert_eq!(matches!(message, SpectatorMessage::Keyframe(_)), turn == 0);
+            if let SpectatorMessage::Delta(delta) = &message {
+                // Moved items are updated, not removed and added again.
+                assert!(delta.removed_items.len() < state.items.len() / 2);
+            }
+            let message = if turn % 2 == 0 {
+                SpectatorMessage::from_json(&message.to_json()).unwrap()
+            } else {
+                SpectatorMessage::from_binary(&message.to_binary()).unwrap()
+            };
+            let decoded = decoder.decode(message).unwrap();
+            let format = TextFormat::all();
+            assert_eq!(decoded.to_text(format), state.to_text(format));
+        }
+        for (i, name) in ["alice", "bob", "carol"]
+            .iter()
+            .take(turn / 10 + 1)
+            .enumerate()
+        {
+            let target = state.items[i % state.items.len()].pos;
+            state.apply_move(PlayerMove {
+                name: name.to_string(),
+                target,
+            });
+        }
+        state = match state.next_turn() {
+            NextTurn::GameState(state) => state,
+            NextTurn::FinalResults(_) => unreachable!(),
+        };
+    }
+    let new_game = GameState::new("another-game", 1, GameConfig::default());
+    assert!(matches!(
+        encoder.encode(&new_game),
+        SpectatorMessage::Keyframe(_)
+    ));
+}
@@ -61,16 +61,15 @@ impl Connection {
         }
     }
 
-    #[must_use]
     pub async fn write<T: std::fmt::Display>(&mut self, s: T) -> Result<()> {
         log::debug!("Sending to {}: {s}", self.addr);
         let s = format!("{}\n", s);
         let mut buf = s.as_bytes();
         while !buf.is_empty() {
-            let n = self.stream.write(&buf).await?;
+            let n = self.stream.write(buf).await?;
             buf = &buf[n..];
         }
         self.stream.flush().await?;
-        return Ok(());
+        Ok(())
     }
 }
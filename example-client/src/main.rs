@@ -36,8 +36,8 @@ fn best_move(game_state: &GameState) -> PlayerMove {
 
 async fn try_one_game(addr: &str, login: &str, password: &str) -> Result<()> {
     log::info!("Trying to connect to {addr}");
-    let stream = TcpStream::connect(addr.clone()).await?;
-    let mut conn = Connection::new(stream, SocketAddr::from_str(&addr).unwrap());
+    let stream = TcpStream::connect(addr).await?;
+    let mut conn = Connection::new(stream, SocketAddr::from_str(addr).unwrap());
 
     conn.read_expect("HELLO").await?;
     conn.write("PLAY").await?;
@@ -75,7 +75,7 @@ async fn one_client(addr: String) {
     let login = format!("{}{}", MY_LOGIN_PREFIX, rand::random::<u8>());
     let password = "very-secure-password";
     loop {
-        match try_one_game(&addr, &login, &password).await {
+        match try_one_game(&addr, &login, password).await {
             Ok(()) => {}
             Err(err) => {
                 log::error!("Connection finished with error: {}", err);
@@ -91,7 +91,7 @@ pub async fn main() -> Result<()> {
     log::info!("Starting client");
     let args = Args::parse();
 
-    let addr = args.addr.unwrap_or(format!("127.0.0.1:7877"));
+    let addr = args.addr.unwrap_or("127.0.0.1:7877".to_owned());
     let mut handles = vec![];
     for _ in 0..args.num_bots {
         handles.push(tokio::spawn(one_client(addr.clone())));
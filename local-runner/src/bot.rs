@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+// Bot process which speaks the same text protocol as the TCP server,
+// but over its stdin/stdout.
+pub struct BotProcess {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    // Bytes of the line which is not fully received yet, they are kept if reading
+    // is cancelled, so `read_token` could be used with a timeout.
+    line: Vec<u8>,
+    tokens: VecDeque<String>,
+    pub cmd: String,
+}
+
+impl BotProcess {
+    pub fn spawn(cmd: &str) -> Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .context(format!("Failed to start bot '{cmd}'"))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout,
+            line: vec![],
+            tokens: Default::default(),
+            cmd: cmd.to_owned(),
+        })
+    }
+
+    pub async fn read_token(&mut self) -> Result<String> {
+        loop {
+            if let Some(token) = self.tokens.pop_front() {
+                return Ok(token);
+            }
+            let n = self.stdout.read_until(b'\n', &mut self.line).await?;
+            if n == 0 {
+                return Err(anyhow::Error::msg("End of stream"));
+            }
+            let line = String::from_utf8(std::mem::take(&mut self.line))?;
+            log::debug!("Received line from '{}': {}", self.cmd, line.trim());
+            self.tokens = line
+                .split_ascii_whitespace()
+                .map(|s| s.to_owned())
+                .collect();
+        }
+    }
+
+    pub fn tokens_left_in_line(&self) -> usize {
+        self.tokens.len()
+    }
+
+    // Tokens which were not read yet from the last received line.
+    pub fn rest_of_line(&mut self) -> Vec<String> {
+        self.tokens.drain(..).collect()
+    }
+
+    pub async fn read<T: FromStr>(&mut self) -> Result<T>
+    where
+        <T as FromStr>::Err: std::fmt::Debug,
+    {
+        let token = self.read_token().await?;
+        match token.parse::<T>() {
+            Ok(res) => Ok(res),
+            Err(err) => Err(std::io::Error::new(ErrorKind::InvalidData, format!("{err:?}")).into()),
+        }
+    }
+
+    pub async fn write<T: std::fmt::Display>(&mut self, s: T) -> Result<()> {
+        log::debug!("Sending to '{}': {s}", self.cmd);
+        self.write_bytes(format!("{s}\n").as_bytes()).await
+    }
+
+    pub async fn write_bytes(&mut self, buf: &[u8]) -> Result<()> {
+        self.stdin.write_all(buf).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+}
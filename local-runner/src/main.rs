@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use clap::Parser;
+use game_common::{
+    game_config::GameConfig,
+    game_state::{GameState, NextTurn, TextFormat},
+    player_move::PlayerMove,
+    point::Point,
+    protocol::{self, Capabilities, Capability},
+    replay::{self, Replay},
+};
+
+mod bot;
+
+use crate::bot::BotProcess;
+use anyhow::Result;
+use tokio::time::Instant;
+
+#[derive(Parser)]
+pub struct Args {
+    // Shell command which starts a bot, e.g. `--bot "python3 player.py"`.
+    #[clap(long = "bot", required_unless_present = "check_replay")]
+    bots: Vec<String>,
+    #[clap(long)]
+    seed: Option<u64>,
+    // JSON file with the rules of the game, see `GameConfig`.
+    #[clap(long)]
+    config: Option<String>,
+    // Overrides one rule, e.g. `--rule max_turns=100`.
+    #[clap(long = "rule")]
+    rules: Vec<String>,
+    // Where to save the replay of the game.
+    #[clap(long)]
+    replay: Option<String>,
+    // Simulate the game from the replay file and check it is the same, instead of playing.
+    #[clap(long)]
+    check_replay: Option<String>,
+}
+
+struct LocalPlayer {
+    bot: BotProcess,
+    login: String,
+    connected: bool,
+    // Already removed from the game after disconnecting.
+    left: bool,
+    caps: Capabilities,
+    // Turns of the states which the bot didn't answer yet, oldest first.
+    unanswered: VecDeque<usize>,
+}
+
+// Bots could take a while to start, but shouldn't be able to stall the game forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Capabilities which the runner supports, all moves are applied in time here.
+const SUPPORTED_CAPABILITIES: &[Capability] = &[
+    Capability::MoveReports,
+    Capability::Json,
+    Capability::Binary,
+    Capability::ItemKinds,
+    Capability::Obstacles,
+    Capability::ItemSpeed,
+    Capability::Resize,
+    Capability::IdleTurns,
+];
+
+async fn handshake(bot: &mut BotProcess, config: &GameConfig) -> Result<(String, Capabilities)> {
+    bot.write("HELLO").await?;
+    let mut caps = Capabilities::default();
+    loop {
+        let cmd = bot.read_token().await?;
+        if cmd == "PLAY" {
+            // All local bots play in the same game, the room is ignored.
+            if bot.tokens_left_in_line() == 1 {
+                bot.read_token().await?;
+            }
+            break;
+        } else if cmd == "CONFIG" {
+            if bot.tokens_left_in_line() == 1 {
+                bot.read_token().await?;
+            }
+            bot.write(config).await?;
+        } else if cmd == "PROTOCOL" {
+            bot.write(protocol::protocol_line(SUPPORTED_CAPABILITIES))
+                .await?;
+        } else if cmd == "ENABLE" {
+            let feature = bot.read_token().await?;
+            match feature.parse() {
+                Ok(cap) if SUPPORTED_CAPABILITIES.contains(&cap) => {
+                    caps.enable(cap);
+                    bot.write(format!("ENABLED {cap}")).await?;
+                }
+                _ => bot.write(format!("UNKNOWN feature '{feature}'")).await?,
+            }
+        } else {
+            anyhow::bail!("Expected 'PLAY', 'CONFIG', 'PROTOCOL' or 'ENABLE', got '{cmd}'");
+        }
+    }
+    let login = bot.read_token().await?;
+    let _password = bot.read_token().await?;
+    Ok((login, caps))
+}
+
+async fn join_players(bot_cmds: &[String], config: &GameConfig) -> Result<Vec<LocalPlayer>> {
+    let mut players: Vec<LocalPlayer> = vec![];
+    for cmd in bot_cmds.iter() {
+        let mut bot = BotProcess::spawn(cmd)?;
+        let (mut login, caps) =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut bot, config))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("Bot '{cmd}' didn't join in {HANDSHAKE_TIMEOUT:?}")
+                })??;
+        // Several copies of the same bot usually use the same login.
+        if players.iter().any(|p| p.login == login) {
+            let mut id = 2;
+            while players.iter().any(|p| p.login == format!("{login}-{id}")) {
+                id += 1;
+            }
+            login = format!("{login}-{id}");
+        }
+        log::info!("Bot '{cmd}' joined as {login}");
+        players.push(LocalPlayer {
+            bot,
+            login,
+            connected: true,
+            left: false,
+            caps,
+            unanswered: VecDeque::new(),
+        });
+    }
+    Ok(players)
+}
+
+async fn send_state(player: &mut LocalPlayer, state: &GameState) -> Result<()> {
+    let mut state = state.clone();
+    state.make_player_first(&player.login);
+    player.unanswered.push_back(state.turn);
+    player
+        .bot
+        .write_bytes(&protocol::encode_state(&state, &player.caps))
+        .await
+}
+
+// Same as `play` on the server does after sending the state. Each command answers
+// the oldest state which the bot didn't answer yet.
+async fn read_command(
+    player: &mut LocalPlayer,
+    turn: usize,
+    deadline: Instant,
+) -> Result<Option<PlayerMove>> {
+    let cmd = player.bot.read_token().await?;
+    let answered_turn = player.unanswered.pop_front().unwrap_or(turn);
+    if cmd == "GO" {
+        let bot = &mut player.bot;
+        let args = bot.rest_of_line();
+        let (move_turn, x, y): (Option<usize>, i32, i32) = match args.len() {
+            0 => (None, bot.read().await?, bot.read().await?),
+            1 => (None, args[0].parse()?, bot.read().await?),
+            2 => (None, args[0].parse()?, args[1].parse()?),
+            3 => (Some(args[0].parse()?), args[1].parse()?, args[2].parse()?),
+            _ => {
+                bot.write("Expected 'GO [X] [Y]' or 'GO [TURN] [X] [Y]'")
+                    .await?;
+                return Ok(None);
+            }
+        };
+        match move_turn {
+            Some(move_turn) if move_turn < turn => {
+                bot.write(format!("STALE {move_turn} {turn}")).await?;
+                return Ok(None);
+            }
+            Some(move_turn) if move_turn > turn => {
+                bot.write(format!("EARLY {move_turn} {turn}")).await?;
+                return Ok(None);
+            }
+            Some(move_turn) => {
+                let time_left = deadline.saturating_duration_since(Instant::now());
+                bot.write(format!("ACK {move_turn} {}", time_left.as_millis()))
+                    .await?;
+            }
+            None if player.caps.has(Capability::MoveReports) => {
+                if answered_turn == turn {
+                    bot.write(format!("MOVE_OK {turn}")).await?;
+                } else {
+                    bot.write(format!("MOVE_LATE {answered_turn} {turn}"))
+                        .await?;
+                }
+            }
+            None => {}
+        }
+        Ok(Some(PlayerMove {
+            name: player.login.clone(),
+            target: Point { x, y },
+        }))
+    } else if cmd == "EXIT" {
+        player.connected = false;
+        Ok(None)
+    } else {
+        player
+            .bot
+            .write(format!("UNKNOWN command '{cmd}', expected 'GO' or 'EXIT'"))
+            .await?;
+        Ok(None)
+    }
+}
+
+// Late answers to the previous states are read first, the latest move is used. A bot which
+// doesn't answer the current state before `deadline` misses the turn, like on the server.
+async fn read_move(
+    player: &mut LocalPlayer,
+    turn: usize,
+    deadline: Instant,
+) -> Result<Option<PlayerMove>> {
+    let mut res = None;
+    while player.connected && !player.unanswered.is_empty() {
+        match tokio::time::timeout_at(deadline, read_command(player, turn, deadline)).await {
+            Ok(player_move) => {
+                if let Some(player_move) = player_move? {
+                    res = Some(player_move);
+                }
+            }
+            Err(_) => {
+                log::warn!("Bot {} didn't answer in time on turn {turn}", player.login);
+                break;
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn check_replay(path: &str) -> Result<()> {
+    let replay = Replay::from_string(&std::fs::read_to_string(path)?)?;
+    replay.verify()?;
+    println!(
+        "Replay {path} is valid: {} states, {}",
+        replay.turns.len(),
+        if replay.results.is_some() {
+            "game finished"
+        } else {
+            "game not finished"
+        }
+    );
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+    if let Some(path) = &args.check_replay {
+        return check_replay(path);
+    }
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let game_id = format!("local-game-{seed}");
+    let config = GameConfig::load(args.config.as_deref(), &args.rules)?;
+    let mut replay_log = replay::header_to_string(seed, &config);
+    let replay_format = TextFormat::for_config(&config);
+    let turn_wait_time = Duration::from_millis(config.turn_wait_ms);
+    let mut state = GameState::new(&game_id, seed, config);
+    log::info!("Starting {game_id} with {} bots", args.bots.len());
+
+    let mut players = join_players(&args.bots, &state.config).await?;
+    let mut moves: Vec<_> = players
+        .iter()
+        .map(|player| PlayerMove {
+            name: player.login.clone(),
+            target: Point::ZERO,
+        })
+        .collect();
+    // Bots which disconnected or sent `EXIT` during the turn.
+    let mut left = vec![];
+
+    let results = loop {
+        replay_log += &state.to_text(replay_format);
+        replay_log += &replay::moves_to_string(&moves);
+        replay_log += &replay::left_to_string(&left);
+        for player_move in moves {
+            state.apply_move(player_move);
+        }
+        for name in left {
+            state.remove_player(&name);
+        }
+        match state.next_turn() {
+            NextTurn::GameState(next_state) => state = next_state,
+            NextTurn::FinalResults(results) => break results,
+        }
+
+        // Send the state to everyone first, so bots think in parallel.
+        for player in players.iter_mut().filter(|p| p.connected) {
+            if let Err(err) = send_state(player, &state).await {
+                log::error!("Bot {} disconnected: {err}", player.login);
+                player.connected = false;
+            }
+        }
+        moves = vec![];
+        let deadline = Instant::now() + turn_wait_time;
+        for player in players.iter_mut().filter(|p| p.connected) {
+            match read_move(player, state.turn, deadline).await {
+                Ok(Some(player_move)) => moves.push(player_move),
+                Ok(None) => {}
+                Err(err) => {
+                    log::error!("Bot {} disconnected: {err}", player.login);
+                    player.connected = false;
+                }
+            }
+        }
+        left = vec![];
+        for player in players.iter_mut() {
+            if !player.connected && !player.left {
+                player.left = true;
+                left.push(player.login.clone());
+            }
+        }
+    };
+    replay_log += &replay::results_to_string(&results);
+    if let Some(path) = &args.replay {
+        std::fs::write(path, replay_log)?;
+    }
+
+    println!("Game {} finished (seed {seed}). Results:", results.game_id);
+    for player in results.players.iter() {
+        println!("{} {}", player.name, player.score);
+    }
+    Ok(())
+}
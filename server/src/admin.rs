@@ -0,0 +1,202 @@
+use std::{collections::BTreeSet, path, sync::Arc};
+
+use anyhow::Result;
+use subtle::ConstantTimeEq;
+use tokio::{
+    fs::{create_dir_all, read_to_string, write},
+    sync::Mutex,
+};
+
+use crate::{
+    clients::Clients,
+    connection::Connection,
+    engine::EngineCommand,
+    read_room_name,
+    rooms::{RoomManager, DEFAULT_ROOM},
+    top_results::TopResults,
+};
+
+// Logins which are not allowed to play, stored one per line.
+pub struct BanList {
+    logins: Mutex<BTreeSet<String>>,
+    filename: String,
+}
+
+impl BanList {
+    pub async fn new(filename: String) -> Result<Self> {
+        create_dir_all(path::Path::new(&filename).parent().unwrap()).await?;
+        let logins = match read_to_string(&filename).await {
+            Ok(content) => content.lines().map(|line| line.to_owned()).collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            logins: Mutex::new(logins),
+            filename,
+        })
+    }
+
+    pub async fn is_banned(&self, login: &str) -> bool {
+        self.logins.lock().await.contains(login)
+    }
+
+    // Returns `false` if nothing changed.
+    async fn update(&self, login: &str, banned: bool) -> Result<bool> {
+        let mut logins = self.logins.lock().await;
+        let changed = if banned {
+            logins.insert(login.to_owned())
+        } else {
+            logins.remove(login)
+        };
+        if changed {
+            let content: String = logins.iter().map(|login| format!("{login}\n")).collect();
+            write(&self.filename, content).await?;
+        }
+        Ok(changed)
+    }
+}
+
+pub struct Admin {
+    // Admin commands are disabled if the password is not set.
+    password: Option<String>,
+    pub clients: Arc<Clients>,
+    pub bans: BanList,
+    top_results: Arc<Mutex<TopResults>>,
+}
+
+impl Admin {
+    pub fn new(
+        password: Option<String>,
+        bans: BanList,
+        top_results: Arc<Mutex<TopResults>>,
+    ) -> Self {
+        Self {
+            password,
+            clients: Arc::new(Clients::default()),
+            bans,
+            top_results,
+        }
+    }
+
+    fn check_password(&self, password: &str) -> Result<()> {
+        let Some(expected) = &self.password else {
+            anyhow::bail!("Admin commands are disabled on this server");
+        };
+        if !bool::from(expected.as_bytes().ct_eq(password.as_bytes())) {
+            anyhow::bail!("Wrong admin password");
+        }
+        Ok(())
+    }
+}
+
+async fn send_to_room(rooms: &RoomManager, room: &str, command: EngineCommand) -> Result<()> {
+    rooms.get(room).await?.tx_commands.send(command).await?;
+    Ok(())
+}
+
+async fn run_command(
+    conn: &mut Connection,
+    cmd: &str,
+    admin: &Admin,
+    rooms: &RoomManager,
+) -> Result<String> {
+    Ok(match cmd {
+        "KICK" => {
+            let login = conn.read_token().await?;
+            let kicked = admin.clients.kick(&login);
+            format!("KICKED {login} {kicked}")
+        }
+        "BAN" => {
+            let login = conn.read_token().await?;
+            admin.bans.update(&login, true).await?;
+            let kicked = admin.clients.kick(&login);
+            format!("BANNED {login} {kicked}")
+        }
+        "UNBAN" => {
+            let login = conn.read_token().await?;
+            if !admin.bans.update(&login, false).await? {
+                anyhow::bail!("Login {login} is not banned");
+            }
+            format!("UNBANNED {login}")
+        }
+        "PAUSE" | "RESUME" | "END_GAME" => {
+            let room = read_room_name(conn).await?;
+            let (command, reply) = match cmd {
+                "PAUSE" => (EngineCommand::Pause, "PAUSED"),
+                "RESUME" => (EngineCommand::Resume, "RESUMED"),
+                _ => (EngineCommand::EndGame, "GAME_ENDED"),
+            };
+            send_to_room(rooms, &room, command).await?;
+            format!("{reply} {room}")
+        }
+        "SET_CONFIG" => {
+            // `SET_CONFIG [ROOM] [KEY]=[VALUE]...`, the room could be omitted.
+            let mut args = conn.rest_of_line();
+            let room = match args.first() {
+                Some(arg) if !arg.contains('=') => args.remove(0),
+                _ => DEFAULT_ROOM.to_owned(),
+            };
+            let mut config = rooms.get(&room).await?.config;
+            for rule in args.iter() {
+                let Some((key, value)) = rule.split_once('=') else {
+                    anyhow::bail!("Expected rule in the [KEY]=[VALUE] format, got '{rule}'");
+                };
+                config.set(key, value)?;
+            }
+            config.validate()?;
+            rooms.set_config(&room, config).await?;
+            format!("CONFIG_SET {room}")
+        }
+        "CLIENTS" => {
+            let clients = admin.clients.list();
+            let mut res = format!("CLIENTS {}", clients.len());
+            for client in clients.iter() {
+                res += &format!(
+                    "\n{} {} {}",
+                    client.addr,
+                    client.login.as_deref().unwrap_or("-"),
+                    client.room.as_deref().unwrap_or("-")
+                );
+            }
+            res
+        }
+        "RELOAD_TOP_RESULTS" => {
+            let num_results = admin.top_results.lock().await.reload().await?;
+            format!("TOP_RESULTS_RELOADED {num_results}")
+        }
+        _ => anyhow::bail!(
+            "UNKNOWN command '{cmd}', expected 'KICK', 'BAN', 'UNBAN', 'PAUSE', 'RESUME', \
+            'END_GAME', 'SET_CONFIG', 'CLIENTS', 'RELOAD_TOP_RESULTS' or 'EXIT'"
+        ),
+    })
+}
+
+// `ADMIN [PASSWORD]` handshake, after that the connection accepts only admin commands.
+pub async fn handle_admin(
+    mut conn: Connection,
+    admin: Arc<Admin>,
+    rooms: Arc<RoomManager>,
+) -> Result<()> {
+    let password = conn.read_token().await?;
+    if let Err(err) = admin.check_password(&password) {
+        log::warn!("Failed admin login from {}", conn.addr);
+        conn.write(format!("{err}")).await?;
+        return Err(err);
+    }
+    log::info!("Admin connected from {}", conn.addr);
+    conn.write("ADMIN_OK").await?;
+    loop {
+        let cmd = conn.read_token().await?;
+        if cmd == "EXIT" {
+            return Ok(());
+        }
+        let reply = match run_command(&mut conn, &cmd, &admin, &rooms).await {
+            Ok(reply) => {
+                log::info!("Admin command {cmd}: {reply}");
+                reply
+            }
+            Err(err) => format!("{err}"),
+        };
+        conn.write(reply).await?;
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{watch, Notify};
+
+#[derive(Clone)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    // Set when the client starts playing.
+    pub login: Option<String>,
+    pub room: Option<String>,
+    kick: Arc<Notify>,
+}
+
+// All TCP and WebSocket connections to the server, so the admin could see and kick them.
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, ClientInfo>>,
+    shutdown: watch::Sender<bool>,
+}
+
+// Why the server closes the connection, sent to the client before closing it.
+pub enum Disconnect {
+    Kicked,
+    Shutdown,
+}
+
+impl fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Disconnect::Kicked => write!(f, "KICKED"),
+            Disconnect::Shutdown => write!(f, "SHUTDOWN"),
+        }
+    }
+}
+
+// Removes the client from the list when the connection is closed.
+pub struct ClientGuard {
+    id: u64,
+    clients: Arc<Clients>,
+    kick: Arc<Notify>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ClientGuard {
+    // Resolves when the client is kicked by the admin or the server is shutting down.
+    pub async fn disconnected(&self) -> Disconnect {
+        let mut shutdown = self.shutdown.clone();
+        tokio::select! {
+            _ = self.kick.notified() => Disconnect::Kicked,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => Disconnect::Shutdown,
+        }
+    }
+
+    pub fn set_room(&self, login: Option<&str>, room: &str) {
+        if let Some(client) = self.clients.clients.lock().unwrap().get_mut(&self.id) {
+            client.login = login.map(|login| login.to_owned());
+            client.room = Some(room.to_owned());
+        }
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            clients: Mutex::new(BTreeMap::new()),
+            shutdown: watch::channel(false).0,
+        }
+    }
+}
+
+impl Clients {
+    pub fn add(self: &Arc<Self>, addr: SocketAddr) -> ClientGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let kick = Arc::new(Notify::new());
+        self.clients.lock().unwrap().insert(
+            id,
+            ClientInfo {
+                addr,
+                login: None,
+                room: None,
+                kick: kick.clone(),
+            },
+        );
+        ClientGuard {
+            id,
+            clients: self.clone(),
+            kick,
+            shutdown: self.shutdown.subscribe(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ClientInfo> {
+        self.clients.lock().unwrap().values().cloned().collect()
+    }
+
+    // Disconnects all players with this login, returns how many connections were closed.
+    pub fn kick(&self, login: &str) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut kicked = 0;
+        for client in clients.values() {
+            if client.login.as_deref() == Some(login) {
+                client.kick.notify_one();
+                kicked += 1;
+            }
+        }
+        kicked
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
@@ -4,13 +4,19 @@ use std::net::SocketAddr;
 use std::str::FromStr;
 
 use anyhow::Result;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
 use tokio::net::TcpStream;
 
+use crate::rate_limiter::ConnectionPermit;
+
+// Longer lines are never valid commands, the connection is closed instead of buffering them.
+pub const MAX_LINE_LEN: usize = 4096;
+
 pub struct Connection {
     stream: BufStream<TcpStream>,
     tokens: VecDeque<String>,
     pub addr: SocketAddr,
+    permit: Option<ConnectionPermit>,
 }
 
 impl Connection {
@@ -20,24 +26,54 @@ impl Connection {
             stream,
             tokens: Default::default(),
             addr,
+            permit: None,
         }
     }
 
+    // Each received line counts as a command of the permit's IP, the connection is closed
+    // if there are too many of them. The permit is released with the connection.
+    pub fn set_permit(&mut self, permit: ConnectionPermit) {
+        self.permit = Some(permit);
+    }
+
+    // Received lines are not logged, they could contain passwords and tokens.
     pub async fn read_token(&mut self) -> Result<String> {
         loop {
             if let Some(token) = self.tokens.pop_front() {
                 return Ok(token);
             }
             let mut line = String::new();
-            let n = self.stream.read_line(&mut line).await?;
+            let n = (&mut self.stream)
+                .take(MAX_LINE_LEN as u64)
+                .read_line(&mut line)
+                .await?;
             if n == 0 {
                 return Err(anyhow::Error::msg("End of stream"));
             }
-            log::debug!("Received line from {}: {}", self.addr, line.trim());
+            if n == MAX_LINE_LEN && !line.ends_with('\n') {
+                let err = anyhow::anyhow!("Line is too long, MAX_LINE_LEN = {MAX_LINE_LEN}");
+                self.write(&err).await?;
+                return Err(err);
+            }
+            if let Some(permit) = &self.permit {
+                if let Err(err) = permit.check_command() {
+                    self.write(&err).await?;
+                    return Err(err);
+                }
+            }
             self.tokens = line.trim().split(" ").map(|s| s.to_owned()).collect();
         }
     }
 
+    pub fn tokens_left_in_line(&self) -> usize {
+        self.tokens.len()
+    }
+
+    // Tokens which were not read yet from the last received line.
+    pub fn rest_of_line(&mut self) -> Vec<String> {
+        self.tokens.drain(..).collect()
+    }
+
     pub async fn read<T: FromStr>(&mut self) -> Result<T>
     where
         <T as FromStr>::Err: std::fmt::Debug,
@@ -61,16 +97,23 @@ impl Connection {
         }
     }
 
-    #[must_use]
     pub async fn write<T: std::fmt::Display>(&mut self, s: T) -> Result<()> {
         log::debug!("Sending to {}: {s}", self.addr);
-        let s = format!("{}\n", s);
-        let mut buf = s.as_bytes();
+        self.write_bytes(format!("{}\n", s).as_bytes()).await
+    }
+
+    // Same as `write`, but the line is not logged, e.g. it contains a login token.
+    pub async fn write_secret<T: std::fmt::Display>(&mut self, s: T) -> Result<()> {
+        log::debug!("Sending a secret line to {}", self.addr);
+        self.write_bytes(format!("{}\n", s).as_bytes()).await
+    }
+
+    pub async fn write_bytes(&mut self, mut buf: &[u8]) -> Result<()> {
         while !buf.is_empty() {
-            let n = self.stream.write(&buf).await?;
+            let n = self.stream.write(buf).await?;
             buf = &buf[n..];
         }
         self.stream.flush().await?;
-        return Ok(());
+        Ok(())
     }
 }
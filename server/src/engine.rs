@@ -1,50 +1,413 @@
-use tokio::fs::{create_dir_all, File};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::fs::{create_dir_all, remove_file, rename, write, File, OpenOptions};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::time::Instant;
 
-use game_common::consts::TURN_WAIT_TIME;
-use game_common::game_state::{self, GameState};
+use game_common::game_config::GameConfig;
+use game_common::game_state::{self, GameResults, GameState, TextFormat};
 use game_common::player_move::PlayerMove;
+use game_common::replay;
 
+use crate::rooms::{self, Rooms, DEFAULT_ROOM};
 use crate::top_results::TopResults;
 
+pub struct MoveRequest {
+    pub player_move: PlayerMove,
+    // If set, the move is rejected unless it arrives during this turn.
+    pub turn: Option<usize>,
+    pub status: Option<oneshot::Sender<MoveStatus>>,
+}
+
+pub enum PlayerEvent {
+    Move(MoveRequest),
+    // `EXIT` command or disconnect, the player stays in the results of the game.
+    Leave { name: String },
+}
+
+// Sent by the admin, applied between turns.
+pub enum EngineCommand {
+    Pause,
+    Resume,
+    // Finishes the current game with the scores players have now.
+    EndGame,
+    // Rules for the next games in the room.
+    SetConfig(Box<GameConfig>),
+    // Saves the current game to continue it after the restart, and stops the engine.
+    Shutdown(oneshot::Sender<()>),
+}
+
+pub enum MoveStatus {
+    Applied { turn: usize, time_left: Duration },
+    Stale { current_turn: usize },
+    // Bots can't answer states they haven't received yet.
+    Early { current_turn: usize },
+}
+
+// Only the latest move of each player is used, but players are still joined
+// in the order of their first moves.
+fn add_move(moves: &mut Vec<PlayerMove>, player_move: PlayerMove) {
+    if let Some(existing) = moves.iter_mut().find(|m| m.name == player_move.name) {
+        existing.target = player_move.target;
+    } else {
+        moves.push(player_move);
+    }
+}
+
+// Accepts moves for the current turn until the deadline. Returns the moves and the players who left,
+// if a player left and moved again during the same turn, only the latest event is used.
+async fn collect_events(
+    rx_events: &mut mpsc::Receiver<PlayerEvent>,
+    turn: usize,
+    deadline: Instant,
+) -> anyhow::Result<(Vec<PlayerMove>, Vec<String>)> {
+    let mut moves = vec![];
+    let mut left: Vec<String> = vec![];
+    let sleep = tokio::time::sleep_until(deadline);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut sleep => return Ok((moves, left)),
+            event = rx_events.recv() => {
+                let request = match event {
+                    Some(PlayerEvent::Move(request)) => request,
+                    Some(PlayerEvent::Leave { name }) => {
+                        moves.retain(|m: &PlayerMove| m.name != name);
+                        if !left.contains(&name) {
+                            left.push(name);
+                        }
+                        continue;
+                    }
+                    None => anyhow::bail!("Events channel is closed"),
+                };
+                let status = match request.turn {
+                    Some(move_turn) if move_turn < turn => MoveStatus::Stale { current_turn: turn },
+                    Some(move_turn) if move_turn > turn => MoveStatus::Early { current_turn: turn },
+                    _ => {
+                        left.retain(|name| *name != request.player_move.name);
+                        add_move(&mut moves, request.player_move);
+                        MoveStatus::Applied {
+                            turn,
+                            time_left: deadline.saturating_duration_since(Instant::now()),
+                        }
+                    }
+                };
+                if let Some(tx_status) = request.status {
+                    // The player could already be disconnected, it is fine.
+                    let _ = tx_status.send(status);
+                }
+            }
+        }
+    }
+}
+
+// Replays of games which are not finished yet. They contain the seed, so bots could
+// predict new items from them, and are moved to `games_dir` only after the results.
+fn running_dir(games_dir: &str) -> String {
+    format!("{games_dir}/running")
+}
+
+fn running_path(games_dir: &str, game_id: &str) -> String {
+    format!("{}/{game_id}.txt", running_dir(games_dir))
+}
+
+// Games which were running when the server stopped, one file per room.
+pub fn checkpoints_dir(games_dir: &str) -> String {
+    format!("{games_dir}/checkpoints")
+}
+
+fn checkpoint_path(games_dir: &str, room: &str) -> String {
+    format!("{}/{room}.txt", checkpoints_dir(games_dir))
+}
+
+// A game saved on shutdown. If the admin changed the rules of the room, the checkpoint
+// starts with them (see `GameConfig`), otherwise the room uses the rules of the server.
+pub struct Checkpoint {
+    pub state: GameState,
+    pub room_config: Option<GameConfig>,
+}
+
+async fn save_checkpoint(
+    games_dir: &str,
+    room: &str,
+    checkpoint: Checkpoint,
+) -> anyhow::Result<()> {
+    create_dir_all(checkpoints_dir(games_dir)).await?;
+    let path = checkpoint_path(games_dir, room);
+    let tmp_path = format!("{path}.tmp");
+    let mut content = checkpoint
+        .room_config
+        .map(|config| config.to_string())
+        .unwrap_or_default();
+    content += &checkpoint.state.to_checkpoint();
+    write(&tmp_path, content).await?;
+    rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+pub fn read_checkpoint(path: &Path) -> anyhow::Result<Checkpoint> {
+    let content = std::fs::read_to_string(path)?;
+    if !content.starts_with("CONFIG") {
+        return Ok(Checkpoint {
+            state: GameState::from_checkpoint(&content)?,
+            room_config: None,
+        });
+    }
+    let Some((_, game)) = content.split_once("END_CONFIG\n") else {
+        anyhow::bail!("Expected END_CONFIG in the checkpoint");
+    };
+    Ok(Checkpoint {
+        state: GameState::from_checkpoint(game)?,
+        room_config: Some(GameConfig::from_string(&content)?),
+    })
+}
+
+// The checkpoint is removed only after the replay is opened, so the same game
+// is not resumed twice, but is not lost if it can't be resumed this time.
+async fn resume_game(games_dir: &str, room: &str, state: &GameState) -> anyhow::Result<File> {
+    let file = OpenOptions::new()
+        .append(true)
+        .open(running_path(games_dir, &state.game_id))
+        .await?;
+    remove_file(checkpoint_path(games_dir, room)).await?;
+    Ok(file)
+}
+
+async fn finish_game(
+    games_dir: &str,
+    mut file: File,
+    results: GameResults,
+    top_results: &Option<Arc<Mutex<TopResults>>>,
+) -> anyhow::Result<()> {
+    file.write_all(replay::results_to_string(&results).as_bytes())
+        .await?;
+    file.flush().await?;
+    drop(file);
+    rename(
+        running_path(games_dir, &results.game_id),
+        format!("{}/{}.txt", games_dir, results.game_id),
+    )
+    .await?;
+    log::info!("Game {} finished! Results:", results.game_id);
+    for player in results.players.iter() {
+        log::info!("{}: {}", player.name, player.score);
+    }
+    if let Some(top_results) = top_results {
+        top_results.lock().await.add_results(results).await?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
+    room: &str,
     tx_game_states: watch::Sender<Option<GameState>>,
-    mut rx_moves: mpsc::Receiver<PlayerMove>,
+    mut rx_events: mpsc::Receiver<PlayerEvent>,
+    mut rx_commands: mpsc::Receiver<EngineCommand>,
     games_dir: &str,
-    top_results: &mut TopResults,
+    mut config: GameConfig,
+    resumed: Option<Checkpoint>,
+    top_results: Option<Arc<Mutex<TopResults>>>,
+    rooms: Option<Arc<Rooms>>,
 ) -> anyhow::Result<()> {
-    log::info!("Running games...");
-    create_dir_all(games_dir).await?;
+    log::info!("Running games in room {room}...");
+    create_dir_all(running_dir(games_dir)).await?;
+    let game_id_format = if room == DEFAULT_ROOM {
+        "game-%Y-%m-%d_%H-%M-%S".to_owned()
+    } else {
+        format!("game-{room}-%Y-%m-%d_%H-%M-%S")
+    };
+    let mut paused = false;
+    let mut next_config = None;
+    // Rules changed by the admin are saved in the checkpoint.
+    let mut config_changed = false;
+    let mut resumed_state = None;
+    if let Some(checkpoint) = resumed {
+        if let Some(room_config) = checkpoint.room_config {
+            config = room_config;
+            config_changed = true;
+        }
+        resumed_state = Some(checkpoint.state);
+    }
     loop {
-        log::info!("New game!");
-        let game_id = chrono::Local::now()
-            .format("game-%Y-%m-%d_%H-%M-%S")
-            .to_string();
-        let mut state = GameState::new(&game_id);
-        let mut file: File = File::create(format!("{}/{}.txt", games_dir, game_id)).await?;
-        loop {
-            log::info!("TURN {}. Players: {}.", state.turn, state.players.len());
-            file.write_all(&state.to_string().as_bytes()).await?;
+        if let Some(new_config) = next_config.take() {
+            config = new_config;
+        }
+        let resumed = match resumed_state.take() {
+            Some(state) => match resume_game(games_dir, room, &state).await {
+                Ok(file) => Some((state, file)),
+                Err(err) => {
+                    log::error!("[{room}] Failed to resume game {}: {err}", state.game_id);
+                    None
+                }
+            },
+            None => None,
+        };
+        // The replay of the resumed game already ends with its current state.
+        let mut state_written = resumed.is_some();
+        let (mut state, mut file) = match resumed {
+            Some((state, file)) => {
+                log::info!("Resuming game {} in room {room}!", state.game_id);
+                (state, file)
+            }
+            None => {
+                log::info!("New game in room {room}!");
+                let game_id = chrono::Local::now().format(&game_id_format).to_string();
+                let seed = rand::random();
+                let mut file = File::create(running_path(games_dir, &game_id)).await?;
+                file.write_all(replay::header_to_string(seed, &config).as_bytes())
+                    .await?;
+                (GameState::new(&game_id, seed, config.clone()), file)
+            }
+        };
+        let game_id = state.game_id.clone();
+        let turn_wait_time = Duration::from_millis(state.config.turn_wait_ms);
+        let replay_format = TextFormat::for_config(&state.config);
+        let results = loop {
+            log::info!(
+                "[{room}] TURN {}. Players: {}.",
+                state.turn,
+                state.players.len()
+            );
+            let deadline = Instant::now() + turn_wait_time;
+            if !state_written {
+                file.write_all(state.to_text(replay_format).as_bytes())
+                    .await?;
+            }
+            state_written = false;
             tx_game_states.send_replace(Some(state.clone()));
-            tokio::time::sleep(TURN_WAIT_TIME).await;
-            // TODO: accept commands in parallel with waiting.
-            while let Ok(player_move) = rx_moves.try_recv() {
+            let (moves, left) = collect_events(&mut rx_events, state.turn, deadline).await?;
+            // While the game is paused, only commands are processed.
+            let mut end_game = false;
+            while !end_game {
+                let command = if paused {
+                    rx_commands.recv().await
+                } else {
+                    rx_commands.try_recv().ok()
+                };
+                let Some(command) = command else {
+                    break;
+                };
+                match command {
+                    EngineCommand::Pause => paused = true,
+                    EngineCommand::Resume => paused = false,
+                    EngineCommand::EndGame => end_game = true,
+                    EngineCommand::SetConfig(new_config) => {
+                        next_config = Some(*new_config);
+                        config_changed = true;
+                    }
+                    EngineCommand::Shutdown(tx_done) => {
+                        // Moves of this turn are dropped, players will send them again after the restart.
+                        let turn = state.turn;
+                        let room_config = next_config.take().unwrap_or(config);
+                        let checkpoint = Checkpoint {
+                            state,
+                            room_config: config_changed.then_some(room_config),
+                        };
+                        save_checkpoint(games_dir, room, checkpoint).await?;
+                        file.flush().await?;
+                        log::info!("[{room}] Game {game_id} is saved on turn {turn}.");
+                        // Nobody waits for the reply if the server is already stopped, it is fine.
+                        let _ = tx_done.send(());
+                        return Ok(());
+                    }
+                }
+            }
+            if end_game {
+                log::info!("[{room}] Game {game_id} was ended by the admin.");
+                file.write_all(replay::ended_to_string().as_bytes()).await?;
+                break GameResults::new(state);
+            }
+            file.write_all(replay::moves_to_string(&moves).as_bytes())
+                .await?;
+            file.write_all(replay::left_to_string(&left).as_bytes())
+                .await?;
+            for player_move in moves {
                 state.apply_move(player_move);
             }
+            for name in left {
+                state.remove_player(&name);
+            }
             match state.next_turn() {
                 game_state::NextTurn::GameState(next_state) => {
                     state = next_state;
                 }
-                game_state::NextTurn::FinalResults(results) => {
-                    log::info!("Game finished! Results:");
-                    for player in results.players.iter() {
-                        log::info!("{}: {}", player.name, player.score);
-                    }
-                    top_results.add_results(results).await?;
-                    break;
-                }
+                game_state::NextTurn::FinalResults(results) => break results,
+            }
+        };
+        let nobody_played = results.players.is_empty();
+        finish_game(games_dir, file, results, &top_results).await?;
+        if let Some(rooms) = &rooms {
+            if nobody_played && rooms::close_if_unused(rooms, room, &tx_game_states).await {
+                log::info!("Closing room {room}, nobody played there.");
+                return Ok(());
             }
         }
     }
 }
+
+#[tokio::test]
+async fn moves_for_other_turns_are_rejected() {
+    use game_common::point::Point;
+
+    let (tx_events, mut rx_events) = mpsc::channel(16);
+    let mut statuses = vec![];
+    for turn in [4, 5, 6] {
+        let (tx_status, rx_status) = oneshot::channel();
+        tx_events
+            .send(PlayerEvent::Move(MoveRequest {
+                player_move: PlayerMove {
+                    name: format!("player{turn}"),
+                    target: Point { x: 1, y: 1 },
+                },
+                turn: Some(turn),
+                status: Some(tx_status),
+            }))
+            .await
+            .unwrap();
+        statuses.push(rx_status);
+    }
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let (moves, _) = collect_events(&mut rx_events, 5, deadline).await.unwrap();
+    assert_eq!(moves.len(), 1);
+    assert_eq!(moves[0].name, "player5");
+    assert!(matches!(
+        statuses[0].try_recv(),
+        Ok(MoveStatus::Stale { current_turn: 5 })
+    ));
+    assert!(matches!(
+        statuses[1].try_recv(),
+        Ok(MoveStatus::Applied { turn: 5, .. })
+    ));
+    assert!(matches!(
+        statuses[2].try_recv(),
+        Ok(MoveStatus::Early { current_turn: 5 })
+    ));
+}
+
+#[tokio::test]
+async fn checkpoints_keep_room_rules() {
+    let dir = std::env::temp_dir().join(format!("checkpoints-test-{}", std::process::id()));
+    let games_dir = dir.to_str().unwrap();
+    let mut room_config = GameConfig::default();
+    room_config.set("max_turns", "100").unwrap();
+    for room_config in [None, Some(room_config)] {
+        let state = GameState::new("game", 1, GameConfig::default());
+        let checkpoint = Checkpoint {
+            state: state.clone(),
+            room_config: room_config.clone(),
+        };
+        save_checkpoint(games_dir, "room", checkpoint)
+            .await
+            .unwrap();
+        let path = checkpoint_path(games_dir, "room");
+        let restored = read_checkpoint(Path::new(&path)).unwrap();
+        assert_eq!(restored.room_config, room_config);
+        assert_eq!(restored.state.to_checkpoint(), state.to_checkpoint());
+    }
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
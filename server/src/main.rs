@@ -1,22 +1,50 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use clap::Parser;
 
+pub mod admin;
+pub mod clients;
 pub mod connection;
 pub mod engine;
 pub mod password_manager;
+pub mod rate_limiter;
+pub mod rooms;
 pub mod top_results;
 
 use game_common::{
-    consts::MAX_LOGIN_LEN, game_state::GameState, player_move::PlayerMove, point::Point,
+    consts::MAX_LOGIN_LEN,
+    game_config::GameConfig,
+    game_state::GameState,
+    player_move::PlayerMove,
+    point::Point,
+    protocol::{self, Capabilities, Capability},
+    state_delta::SpectatorEncoder,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::{mpsc, watch},
+    signal,
+    sync::{mpsc, oneshot, watch, Mutex},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
 };
-use tokio_tungstenite::{accept_async, tungstenite::Message};
 
-use crate::{connection::Connection, password_manager::PasswordManager, top_results::TopResults};
+use crate::{
+    admin::{Admin, BanList},
+    clients::{ClientGuard, Clients},
+    connection::Connection,
+    engine::{MoveRequest, MoveStatus, PlayerEvent},
+    password_manager::PasswordManager,
+    rate_limiter::{Limits, RateLimiter},
+    rooms::{RoomManager, DEFAULT_ROOM},
+    top_results::TopResults,
+};
 use anyhow::Result;
 use futures_util::{stream::StreamExt, SinkExt};
 
@@ -28,6 +56,22 @@ pub struct Args {
     websocket_port: Option<u16>,
     #[clap(long)]
     base_dir: Option<String>,
+    // JSON file with the rules of the game, see `GameConfig`.
+    #[clap(long)]
+    config: Option<String>,
+    // Overrides one rule, e.g. `--rule max_turns=100`.
+    #[clap(long = "rule")]
+    rules: Vec<String>,
+    // Enables `ADMIN [PASSWORD]` commands.
+    #[clap(long, env = "ADMIN_PASSWORD")]
+    admin_password: Option<String>,
+    // Limits for each IP address, 0 means no limit.
+    #[clap(long, default_value_t = 50)]
+    max_connections_per_ip: usize,
+    #[clap(long, default_value_t = 60)]
+    max_new_connections_per_minute: usize,
+    #[clap(long, default_value_t = 50)]
+    max_commands_per_second: u32,
 }
 
 fn validate_login(login: &str) -> Result<()> {
@@ -43,50 +87,136 @@ fn validate_login(login: &str) -> Result<()> {
     Ok(())
 }
 
+// Reads `[LOGIN] [PASSWORD]` after `PLAY`, unless the client already used `LOGIN_TOKEN`.
+async fn authenticate(
+    conn: &mut Connection,
+    password_manager: &PasswordManager,
+    admin: &Admin,
+    login: Option<String>,
+) -> Result<String> {
+    let login = match login {
+        Some(login) => login,
+        None => {
+            let login = conn.read_token().await?;
+            log::info!("Got login: {login}");
+            validate_login(&login)?;
+            let password = conn.read_token().await?;
+            if let Err(err) = password_manager
+                .check_password(&login, &password, &conn.addr.to_string())
+                .await
+            {
+                conn.write(&format!("{:?}", err)).await?;
+                return Err(err);
+            }
+            login
+        }
+    };
+    if admin.bans.is_banned(&login).await {
+        conn.write(format!("Login {login} is banned")).await?;
+        anyhow::bail!("Banned login {login}");
+    }
+    Ok(login)
+}
+
 async fn handle_tcp_play(
     mut conn: Connection,
+    rx_game_stats: watch::Receiver<Option<GameState>>,
+    tx_events: mpsc::Sender<PlayerEvent>,
+    login: String,
+    caps: Capabilities,
+    client: &ClientGuard,
+) -> Result<()> {
+    let res = tokio::select! {
+        res = play(&mut conn, rx_game_stats, &tx_events, &login, &caps) => res,
+        reason = client.disconnected() => {
+            // The client could be already disconnected, it is fine.
+            let _ = conn.write(&reason).await;
+            Err(anyhow::anyhow!("Disconnected by the server: {reason}"))
+        }
+    };
+    // The player leaves the game both after `EXIT` and after disconnecting.
+    tx_events.send(PlayerEvent::Leave { name: login }).await?;
+    res
+}
+
+async fn play(
+    conn: &mut Connection,
     mut rx_game_stats: watch::Receiver<Option<GameState>>,
-    tx_moves: mpsc::Sender<PlayerMove>,
-    password_manager: Arc<PasswordManager>,
+    tx_events: &mpsc::Sender<PlayerEvent>,
+    login: &str,
+    caps: &Capabilities,
 ) -> Result<()> {
-    let login = conn.read_token().await?;
-    log::info!("Got login: {login}");
-    validate_login(&login)?;
-    let password = conn.read_token().await?;
-    log::debug!("Got password: {password}");
-    if let Err(err) = password_manager
-        .check_password(&login, &password, &conn.addr.to_string())
-        .await
-    {
-        conn.write(&format!("{:?}", err)).await?;
-        return Err(err);
-    }
     let mut state;
     loop {
         rx_game_stats.changed().await?;
         state = rx_game_stats.borrow().clone();
         if let Some(state) = &mut state {
-            if !state.make_player_first(&login) {
-                tx_moves
-                    .send(PlayerMove {
-                        name: login.clone(),
-                        target: Point::ZERO,
-                    })
+            if !state.make_player_first(login) {
+                tx_events
+                    .send(PlayerEvent::Move(MoveRequest {
+                        player_move: PlayerMove {
+                            name: login.to_owned(),
+                            target: Point::ZERO,
+                        },
+                        turn: None,
+                        status: None,
+                    }))
                     .await?;
                 continue;
             }
-            conn.write(state.to_string()).await?;
+            conn.write_bytes(&protocol::encode_state(state, caps))
+                .await?;
 
             let cmd = conn.read_token().await?;
             if cmd == "GO" {
-                let x: i32 = conn.read().await?;
-                let y: i32 = conn.read().await?;
-                tx_moves
-                    .send(PlayerMove {
-                        name: login.clone(),
-                        target: Point { x, y },
-                    })
+                // Either `GO [X] [Y]` or `GO [TURN] [X] [Y]`. Old clients could also
+                // split `GO [X] [Y]` into several lines.
+                let args = conn.rest_of_line();
+                let (turn, x, y) = match args.len() {
+                    0 => (None, conn.read().await?, conn.read().await?),
+                    1 => (None, args[0].parse()?, conn.read().await?),
+                    2 => (None, args[0].parse()?, args[1].parse()?),
+                    3 => (Some(args[0].parse()?), args[1].parse()?, args[2].parse()?),
+                    _ => {
+                        conn.write("Expected 'GO [X] [Y]' or 'GO [TURN] [X] [Y]'")
+                            .await?;
+                        continue;
+                    }
+                };
+                let (tx_status, rx_status) = oneshot::channel();
+                tx_events
+                    .send(PlayerEvent::Move(MoveRequest {
+                        player_move: PlayerMove {
+                            name: login.to_owned(),
+                            target: Point { x, y },
+                        },
+                        turn,
+                        status: Some(tx_status),
+                    }))
                     .await?;
+                match (turn, rx_status.await?) {
+                    (Some(turn), MoveStatus::Applied { time_left, .. }) => {
+                        conn.write(format!("ACK {turn} {}", time_left.as_millis()))
+                            .await?;
+                    }
+                    (Some(turn), MoveStatus::Stale { current_turn }) => {
+                        conn.write(format!("STALE {turn} {current_turn}")).await?;
+                    }
+                    (Some(turn), MoveStatus::Early { current_turn }) => {
+                        conn.write(format!("EARLY {turn} {current_turn}")).await?;
+                    }
+                    (None, MoveStatus::Applied { turn, .. })
+                        if caps.has(Capability::MoveReports) =>
+                    {
+                        if turn == state.turn {
+                            conn.write(format!("MOVE_OK {turn}")).await?;
+                        } else {
+                            conn.write(format!("MOVE_LATE {} {turn}", state.turn))
+                                .await?;
+                        }
+                    }
+                    (None, _) => {}
+                }
             } else if cmd == "EXIT" {
                 return Ok(());
             } else {
@@ -100,65 +230,198 @@ async fn handle_tcp_play(
 async fn handle_tcp_watch(
     mut conn: Connection,
     mut rx_game_states: watch::Receiver<Option<GameState>>,
+    caps: Capabilities,
+    client: &ClientGuard,
 ) -> Result<()> {
     let mut state;
     loop {
-        rx_game_states.changed().await?;
+        tokio::select! {
+            res = rx_game_states.changed() => res?,
+            reason = client.disconnected() => {
+                conn.write(&reason).await?;
+                return Ok(());
+            }
+        }
         state = rx_game_states.borrow().clone();
         if let Some(state) = &mut state {
-            conn.write(state.to_string()).await?;
+            conn.write_bytes(&protocol::encode_state(state, &caps))
+                .await?;
         }
     }
 }
 
+// Commands to manage accounts, which could be sent before `PLAY`. Returns the reply.
+async fn handle_account_command(
+    conn: &mut Connection,
+    cmd_type: &str,
+    password_manager: &PasswordManager,
+) -> Result<String> {
+    let login = conn.read_token().await?;
+    let password = conn.read_token().await?;
+    let res = match cmd_type {
+        "REGISTER" => match validate_login(&login) {
+            Ok(()) => password_manager
+                .register(&login, &password, &conn.addr.to_string())
+                .await
+                .map(|()| format!("REGISTERED {login}")),
+            Err(err) => Err(err),
+        },
+        "CHANGE_PASSWORD" => {
+            let new_password = conn.read_token().await?;
+            password_manager
+                .change_password(&login, &password, &new_password)
+                .await
+                .map(|()| format!("PASSWORD_CHANGED {login}"))
+        }
+        "CREATE_TOKEN" => password_manager
+            .create_token(&login, &password)
+            .await
+            .map(|token| format!("TOKEN {token}")),
+        "REVOKE_TOKEN" => {
+            let token = conn.read_token().await?;
+            password_manager
+                .revoke_token(&login, &password, &token)
+                .await
+                .map(|()| "TOKEN_REVOKED".to_owned())
+        }
+        _ => unreachable!(),
+    };
+    Ok(res.unwrap_or_else(|err| format!("{err}")))
+}
+
+// `PLAY` and `WATCH` could be followed by the room name on the same line.
+async fn read_room_name(conn: &mut Connection) -> Result<String> {
+    if conn.tokens_left_in_line() == 1 {
+        conn.read_token().await
+    } else {
+        Ok(DEFAULT_ROOM.to_owned())
+    }
+}
+
 async fn handle_tcp_connection(
     mut conn: Connection,
-    rx_game_states: watch::Receiver<Option<GameState>>,
-    tx_moves: mpsc::Sender<PlayerMove>,
+    rooms: Arc<RoomManager>,
     password_manager: Arc<PasswordManager>,
+    admin: Arc<Admin>,
 ) -> Result<()> {
+    let client = admin.clients.add(conn.addr);
     conn.write("HELLO").await?;
+    let mut caps = Capabilities::default();
+    let mut login = None;
     loop {
         let cmd_type = conn.read_token().await?;
-        if cmd_type == "WATCH" {
-            handle_tcp_watch(conn, rx_game_states).await?;
+        if cmd_type == "WATCH" || cmd_type == "PLAY" {
+            let room_name = read_room_name(&mut conn).await?;
+            let room = if cmd_type == "PLAY" {
+                rooms.get_or_create(&room_name).await
+            } else {
+                rooms.get(&room_name).await
+            };
+            let room = match room {
+                Ok(room) => room,
+                Err(err) => {
+                    conn.write(format!("{err}")).await?;
+                    continue;
+                }
+            };
+            if cmd_type == "WATCH" {
+                client.set_room(None, &room_name);
+                handle_tcp_watch(conn, room.rx_game_states, caps, &client).await?;
+            } else {
+                let login = authenticate(&mut conn, &password_manager, &admin, login).await?;
+                client.set_room(Some(&login), &room_name);
+                handle_tcp_play(
+                    conn,
+                    room.rx_game_states,
+                    room.tx_events,
+                    login,
+                    caps,
+                    &client,
+                )
+                .await?;
+            }
             break;
         }
-        if cmd_type == "PLAY" {
-            handle_tcp_play(conn, rx_game_states, tx_moves, password_manager).await?;
-            break;
+        if cmd_type == "CONFIG" {
+            let room_name = read_room_name(&mut conn).await?;
+            match rooms.get(&room_name).await {
+                Ok(room) => conn.write(room.config.to_string()).await?,
+                Err(err) => conn.write(format!("{err}")).await?,
+            }
+            continue;
+        }
+        if cmd_type == "PROTOCOL" {
+            conn.write(protocol::protocol_line(Capability::ALL)).await?;
+            continue;
+        }
+        if cmd_type == "ENABLE" {
+            let feature = conn.read_token().await?;
+            match feature.parse() {
+                Ok(cap) => {
+                    caps.enable(cap);
+                    conn.write(format!("ENABLED {cap}")).await?;
+                }
+                Err(err) => conn.write(format!("{err}")).await?,
+            }
+            continue;
+        }
+        if [
+            "REGISTER",
+            "CHANGE_PASSWORD",
+            "CREATE_TOKEN",
+            "REVOKE_TOKEN",
+        ]
+        .contains(&cmd_type.as_str())
+        {
+            let reply = handle_account_command(&mut conn, &cmd_type, &password_manager).await?;
+            // `CREATE_TOKEN` replies with the new token.
+            conn.write_secret(reply).await?;
+            continue;
+        }
+        if cmd_type == "ADMIN" {
+            return admin::handle_admin(conn, admin, rooms).await;
+        }
+        if cmd_type == "LOGIN_TOKEN" {
+            let token = conn.read_token().await?;
+            match password_manager.check_token(&token).await {
+                Ok(token_login) => {
+                    conn.write(format!("LOGGED_IN {token_login}")).await?;
+                    login = Some(token_login);
+                }
+                Err(err) => conn.write(format!("{err}")).await?,
+            }
+            continue;
         }
-        conn.write(format!("Expected 'WATCH' or 'PLAY', got '{}'", cmd_type))
-            .await?;
+        conn.write(format!(
+            "Expected 'WATCH', 'PLAY', 'CONFIG', 'PROTOCOL', 'ENABLE', 'REGISTER', 'CHANGE_PASSWORD', \
+            'CREATE_TOKEN', 'REVOKE_TOKEN', 'LOGIN_TOKEN' or 'ADMIN', got '{}'",
+            cmd_type
+        ))
+        .await?;
     }
     Ok(())
 }
 
 async fn run_tcp_server(
     port: u16,
-    rx_game_states: watch::Receiver<Option<GameState>>,
-    tx_moves: mpsc::Sender<PlayerMove>,
+    rooms: Arc<RoomManager>,
     password_manager: Arc<PasswordManager>,
+    admin: Arc<Admin>,
+    limiter: Arc<RateLimiter>,
 ) {
     let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
     log::info!("Listening to port {port}");
 
-    // TODO: ip-based rate limiting.
     loop {
-        let (tcp_stream, addr) = listener.accept().await.unwrap();
+        let (tcp_stream, addr, permit) = rate_limiter::accept(&listener, &limiter).await.unwrap();
         log::info!("New connection from {addr:?}");
-        let rx_game_states = rx_game_states.clone();
-        let tx_moves = tx_moves.clone();
+        let rooms = rooms.clone();
         let password_manager = password_manager.clone();
+        let admin = admin.clone();
+        let mut conn = Connection::new(tcp_stream, addr);
+        conn.set_permit(permit);
         tokio::spawn(async move {
-            match handle_tcp_connection(
-                Connection::new(tcp_stream, addr.clone()),
-                rx_game_states,
-                tx_moves,
-                password_manager,
-            )
-            .await
-            {
+            match handle_tcp_connection(conn, rooms, password_manager, admin).await {
                 Ok(()) => log::info!("Connection {addr:?} closed successfully."),
                 Err(e) => log::info!("Some error happened with connection to {addr:?}: {e}"),
             }
@@ -166,38 +429,128 @@ async fn run_tcp_server(
     }
 }
 
+// The room is chosen by the path: `ws://[HOST]:[PORT]/[ROOM]`. Spectators get a keyframe and
+// then deltas (see `state_delta`) as JSON text messages, or as binary messages with `?format=binary`.
+// The error type of the handshake callback is defined by tungstenite.
+#[allow(clippy::result_large_err)]
 async fn handle_one_web_socket_conn(
     raw_stream: TcpStream,
-    mut rx_game_states: watch::Receiver<Option<GameState>>,
+    addr: SocketAddr,
+    rooms: Arc<RoomManager>,
+    limiter: Arc<RateLimiter>,
+    clients: Arc<Clients>,
 ) -> Result<()> {
-    let ws_stream = accept_async(raw_stream).await.unwrap();
+    let mut path = String::new();
+    let mut binary = false;
+    let mut _permit = None;
+    let mut client_addr = addr;
+    let ws_stream = accept_hdr_async(raw_stream, |request: &Request, response: Response| {
+        // Behind nginx all connections come from localhost, the real IP is in the header.
+        let real_ip = request
+            .headers()
+            .get("X-Real-IP")
+            .and_then(|ip| ip.to_str().ok()?.parse().ok());
+        let ip = match real_ip {
+            // The port of the client is not known behind nginx.
+            Some(ip) if addr.ip().is_loopback() => {
+                client_addr = SocketAddr::new(ip, 0);
+                ip
+            }
+            _ => addr.ip(),
+        };
+        // Connections over the limits get an HTTP error instead of the handshake.
+        match limiter.try_connect(ip) {
+            Ok(permit) => _permit = Some(permit),
+            Err(err) => {
+                log::info!("Rejected websocket connection from {ip}: {err}");
+                let mut error = ErrorResponse::new(Some(err.to_string()));
+                *error.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                return Err(error);
+            }
+        }
+        path = request.uri().path().to_owned();
+        binary = request
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|param| param == "format=binary"));
+        Ok(response)
+    })
+    .await?;
     let (mut write, _read) = ws_stream.split();
 
+    let room_name = match path.trim_matches('/') {
+        "" => DEFAULT_ROOM,
+        name => name,
+    };
+    let room = rooms.get(room_name).await?;
+    let mut rx_game_states = room.rx_game_states;
+    let client = clients.add(client_addr);
+    client.set_room(None, room_name);
+
+    let mut encoder = SpectatorEncoder::new(room.spectator_deltas);
     let mut state;
     loop {
-        rx_game_states.changed().await?;
+        tokio::select! {
+            res = rx_game_states.changed() => res?,
+            reason = client.disconnected() => {
+                let frame = CloseFrame {
+                    code: CloseCode::Away,
+                    reason: reason.to_string().into(),
+                };
+                write.send(Message::Close(Some(frame))).await?;
+                return Ok(());
+            }
+        }
         state = rx_game_states.borrow().clone();
         if let Some(state) = &mut state {
-            write.send(Message::Text(state.to_string())).await?;
+            let message = encoder.encode(state);
+            let message = if binary {
+                Message::Binary(message.to_binary())
+            } else {
+                Message::Text(message.to_json())
+            };
+            write.send(message).await?;
         }
     }
 }
 
-async fn run_web_sockets_server(port: u16, rx_game_states: watch::Receiver<Option<GameState>>) {
+async fn run_web_sockets_server(
+    port: u16,
+    rooms: Arc<RoomManager>,
+    limiter: Arc<RateLimiter>,
+    clients: Arc<Clients>,
+) {
     let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
     log::info!("WebSocket server listening on ws://127.0.0.1:{port}");
 
     while let Ok((raw_stream, addr)) = listener.accept().await {
         log::info!("New websocket connection from {addr:?}");
 
-        let rx_game_states = rx_game_states.clone();
+        let rooms = rooms.clone();
+        let limiter = limiter.clone();
+        let clients = clients.clone();
         tokio::spawn(async move {
-            let res = handle_one_web_socket_conn(raw_stream, rx_game_states).await;
+            let res = handle_one_web_socket_conn(raw_stream, addr, rooms, limiter, clients).await;
             log::info!("Websocket connection from {addr:?} closed: {res:?}");
         });
     }
 }
 
+// SIGINT (Ctrl+C) or SIGTERM (`killall game-server`).
+async fn wait_for_shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = signal::ctrl_c() => res?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+    Ok(())
+}
+
 pub const DEFAULT_TCP_PORT: u16 = 7877;
 pub const DEFAULT_WEB_SOCKET_PORT: u16 = 7878;
 
@@ -208,29 +561,60 @@ pub async fn main() -> Result<()> {
     let args = Args::parse();
 
     let base_dir = args.base_dir.unwrap_or("data".to_owned());
-    let password_manager =
-        Arc::new(PasswordManager::new(format!("{base_dir}/passwords.txt")).await?);
+    let password_manager = Arc::new(
+        PasswordManager::new(
+            format!("{base_dir}/passwords.txt"),
+            format!("{base_dir}/tokens.txt"),
+        )
+        .await?,
+    );
     let games_dir = format!("{base_dir}/games");
-    let mut top_results = TopResults::new(format!("{base_dir}/top_results.txt")).await?;
+    let config = GameConfig::load(args.config.as_deref(), &args.rules)?;
+    let top_results = Arc::new(Mutex::new(
+        TopResults::new(format!("{base_dir}/top_results.txt")).await?,
+    ));
+    let bans = BanList::new(format!("{base_dir}/bans.txt")).await?;
+    let admin = Arc::new(Admin::new(args.admin_password, bans, top_results.clone()));
+    let clients = admin.clients.clone();
+
+    let limits = Limits {
+        max_connections: args.max_connections_per_ip,
+        max_new_connections_per_minute: args.max_new_connections_per_minute,
+        max_commands_per_second: args.max_commands_per_second,
+    };
 
     let tcp_port = args.tcp_port.unwrap_or(DEFAULT_TCP_PORT);
     let web_socket_port = args.websocket_port.unwrap_or(DEFAULT_WEB_SOCKET_PORT);
 
-    let (tx_game_states, rx_game_states) = watch::channel::<Option<GameState>>(None);
-    let (tx_moves, rx_moves) = mpsc::channel::<PlayerMove>(1024);
-    tokio::spawn(async move {
-        engine::run(tx_game_states, rx_moves, &games_dir, &mut top_results).await
-    });
+    let rooms = Arc::new(RoomManager::new(games_dir, config, top_results));
 
     let tcp_server = tokio::spawn({
-        let rx_game_states = rx_game_states.clone();
-        async move { run_tcp_server(tcp_port, rx_game_states, tx_moves, password_manager).await }
+        let rooms = rooms.clone();
+        let limiter = RateLimiter::new(limits);
+        async move { run_tcp_server(tcp_port, rooms, password_manager, admin, limiter).await }
     });
 
-    let websockets_server =
-        tokio::spawn(async move { run_web_sockets_server(web_socket_port, rx_game_states).await });
+    let websockets_server = tokio::spawn({
+        let rooms = rooms.clone();
+        // Spectators are counted separately from players.
+        let limiter = RateLimiter::new(limits);
+        let clients = clients.clone();
+        async move { run_web_sockets_server(web_socket_port, rooms, limiter, clients).await }
+    });
 
-    tokio::try_join!(tcp_server, websockets_server)?;
+    tokio::select! {
+        res = async { tokio::try_join!(tcp_server, websockets_server) } => {
+            res?;
+        }
+        res = wait_for_shutdown_signal() => {
+            res?;
+            log::info!("Shutting down...");
+            // Clients are notified first, otherwise they would see stopped games as a crash.
+            clients.shutdown();
+            rooms.shutdown().await;
+            log::info!("All games are saved, they will continue after the restart.");
+        }
+    }
 
     Ok(())
 }
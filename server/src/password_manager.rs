@@ -1,84 +1,425 @@
 use std::{collections::HashMap, path};
 
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
 use game_common::consts::MAX_PASSWORD_LEN;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 use tokio::{
-    fs::{create_dir_all, File, OpenOptions},
+    fs::{create_dir_all, rename, File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
     sync::Mutex,
 };
 
+const TOKEN_BYTES: usize = 24;
+
+struct Entry {
+    ip: String,
+    // Argon2 hash in PHC format, or a plaintext password from old files.
+    password: String,
+}
+
 pub struct PasswordManager {
-    passwords: Mutex<HashMap<String, String>>,
+    filename: String,
+    passwords: Mutex<HashMap<String, Entry>>,
     file: Mutex<File>,
+    tokens_filename: String,
+    // SHA-256 of the token -> login. Tokens are random, so a fast hash is enough.
+    tokens: Mutex<HashMap<String, String>>,
+    tokens_file: Mutex<File>,
+}
+
+fn hash_password(password: String) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow::anyhow!("Failed to hash password: {err}"))?;
+    Ok(hash.to_string())
+}
+
+// Returns `None` if `stored` is not a hash, but a plaintext password.
+fn verify_hash(stored: &str, password: &str) -> Option<bool> {
+    let hash = PasswordHash::new(stored).ok()?;
+    Some(
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+    )
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn validate_password(password: &str) -> anyhow::Result<()> {
+    if password == "GO" {
+        anyhow::bail!("Please don't use 'GO' as your password!");
+    }
+    if password.len() > MAX_PASSWORD_LEN {
+        anyhow::bail!(
+            "Password is too long. MAX_PASSWORD_LEN = {}",
+            MAX_PASSWORD_LEN
+        );
+    }
+    Ok(())
+}
+
+async fn open_file(filename: &str) -> anyhow::Result<File> {
+    create_dir_all(path::Path::new(filename).parent().unwrap()).await?;
+    Ok(OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(filename)
+        .await?)
+}
+
+async fn read_lines(file: &mut File) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut lines = String::new();
+    file.read_to_string(&mut lines).await?;
+    Ok(lines
+        .lines()
+        .map(|line| line.split(' ').map(|s| s.to_string()).collect())
+        .collect())
+}
+
+async fn append_line(file: &Mutex<File>, line: String) -> anyhow::Result<()> {
+    let mut guard = file.lock().await;
+    guard.write_all(line.as_bytes()).await?;
+    guard.flush().await?;
+    Ok(())
+}
+
+// Writes the whole file from scratch, so nothing removed from it is left on disk.
+async fn rewrite_file(filename: &str, file: &mut File, content: String) -> anyhow::Result<()> {
+    let tmp_filename = format!("{filename}.tmp");
+    let mut tmp_file = File::create(&tmp_filename).await?;
+    tmp_file.write_all(content.as_bytes()).await?;
+    tmp_file.sync_all().await?;
+    rename(&tmp_filename, filename).await?;
+    *file = open_file(filename).await?;
+    Ok(())
 }
 
 impl PasswordManager {
-    pub async fn new(filename: String) -> anyhow::Result<Self> {
-        create_dir_all(path::Path::new(&filename).parent().unwrap()).await?;
-        let passwords = Mutex::new(HashMap::new());
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)
-            .await?;
-        let mut lines = String::new();
-        file.read_to_string(&mut lines).await?;
-        for line in lines.lines() {
-            let mut parts = line.split(' ');
-            let login = parts.next().unwrap();
-            let _ip = parts.next().unwrap();
-            let password = parts.next().unwrap();
-            passwords
-                .lock()
-                .await
-                .insert(login.to_string(), password.to_string());
+    pub async fn new(filename: String, tokens_filename: String) -> anyhow::Result<Self> {
+        let mut passwords = HashMap::new();
+        let mut file = open_file(&filename).await?;
+        for parts in read_lines(&mut file).await? {
+            let login = parts[0].clone();
+            let ip = parts[1].clone();
+            let password = parts[2].clone();
+            passwords.insert(login, Entry { ip, password });
+        }
+        let mut tokens = HashMap::new();
+        let mut tokens_file = open_file(&tokens_filename).await?;
+        for parts in read_lines(&mut tokens_file).await? {
+            let login = parts[0].clone();
+            let token_hash = parts[1].clone();
+            tokens.insert(token_hash, login);
         }
         Ok(PasswordManager {
-            passwords,
+            filename,
+            passwords: Mutex::new(passwords),
             file: Mutex::new(file),
+            tokens_filename,
+            tokens: Mutex::new(tokens),
+            tokens_file: Mutex::new(tokens_file),
         })
     }
 
+    // Used by `PLAY`: checks the password, or registers a new login if it is not used yet.
     pub async fn check_password(
         &self,
         login: &str,
         password: &str,
         ip: &str,
     ) -> anyhow::Result<()> {
-        if password == "GO" {
-            anyhow::bail!("Please don't use 'GO' as your password!");
+        // Two connections could try to register the same new login at once.
+        if self.passwords.lock().await.contains_key(login)
+            || !self.try_register(login, password, ip).await?
+        {
+            self.verify_password(login, password).await?;
         }
-        let expected_password = self.passwords.lock().await.get(login).cloned();
-        if let Some(expected_password) = expected_password {
-            if expected_password == password {
-                return Ok(());
-            } else {
-                return Err(anyhow::anyhow!(
-                    "Wrong password. Use the same password as before."
-                ));
-            }
-        } else {
-            if password.len() > MAX_PASSWORD_LEN {
-                anyhow::bail!(
-                    "Password is too long. MAX_PASSWORD_LEN = {}",
-                    MAX_PASSWORD_LEN
-                );
+        Ok(())
+    }
+
+    pub async fn register(&self, login: &str, password: &str, ip: &str) -> anyhow::Result<()> {
+        if self.passwords.lock().await.contains_key(login)
+            || !self.try_register(login, password, ip).await?
+        {
+            anyhow::bail!("Login {login} is already registered.");
+        }
+        Ok(())
+    }
+
+    // Returns `false` if somebody registered the same login while we were hashing.
+    async fn try_register(&self, login: &str, password: &str, ip: &str) -> anyhow::Result<bool> {
+        validate_password(password)?;
+        let password = password.to_string();
+        let hash = tokio::task::spawn_blocking(move || hash_password(password)).await??;
+        let line = format!("{login} {ip} {hash}\n");
+        {
+            let mut passwords = self.passwords.lock().await;
+            if passwords.contains_key(login) {
+                return Ok(false);
             }
-            self.passwords
-                .lock()
-                .await
-                .insert(login.to_string(), password.to_string());
-            let mut guard = self.file.lock().await;
-            guard
-                .write_all(format!("{login} {ip} {password}\n").as_bytes())
-                .await?;
-            guard.flush().await?;
-            log::info!(
-                "Updated passwords file, total {} passwords.",
-                self.passwords.lock().await.len()
+            passwords.insert(
+                login.to_string(),
+                Entry {
+                    ip: ip.to_string(),
+                    password: hash,
+                },
             );
-            Ok(())
         }
+        append_line(&self.file, line).await?;
+        log::info!(
+            "Updated passwords file, total {} passwords.",
+            self.passwords.lock().await.len()
+        );
+        Ok(true)
+    }
+
+    pub async fn verify_password(&self, login: &str, password: &str) -> anyhow::Result<()> {
+        // Such passwords could never be registered, so don't spend time on hashing them.
+        if password.len() > MAX_PASSWORD_LEN {
+            anyhow::bail!("Wrong password. Use the same password as before.");
+        }
+        let stored = self
+            .passwords
+            .lock()
+            .await
+            .get(login)
+            .map(|entry| entry.password.clone());
+        let Some(stored) = stored else {
+            anyhow::bail!("Unknown login {login}.");
+        };
+        let password = password.to_string();
+        // Hashing is slow, so don't block other connections.
+        let (correct, new_hash) =
+            tokio::task::spawn_blocking(move || match verify_hash(&stored, &password) {
+                Some(correct) => Ok((correct, None)),
+                None => {
+                    let correct: bool = stored.as_bytes().ct_eq(password.as_bytes()).into();
+                    let new_hash = if correct {
+                        Some(hash_password(password)?)
+                    } else {
+                        None
+                    };
+                    anyhow::Ok((correct, new_hash))
+                }
+            })
+            .await??;
+        if !correct {
+            anyhow::bail!("Wrong password. Use the same password as before.");
+        }
+        if let Some(new_hash) = new_hash {
+            self.set_password_hash(login, new_hash).await?;
+            log::info!("Replaced plaintext password of {login} with a hash.");
+        }
+        Ok(())
+    }
+
+    pub async fn change_password(
+        &self,
+        login: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> anyhow::Result<()> {
+        validate_password(new_password)?;
+        self.verify_password(login, old_password).await?;
+        let new_password = new_password.to_string();
+        let hash = tokio::task::spawn_blocking(move || hash_password(new_password)).await??;
+        self.set_password_hash(login, hash).await?;
+        log::info!("Changed password of {login}.");
+        Ok(())
+    }
+
+    async fn set_password_hash(&self, login: &str, hash: String) -> anyhow::Result<()> {
+        let mut guard = self.file.lock().await;
+        let mut content = String::new();
+        {
+            let mut passwords = self.passwords.lock().await;
+            if let Some(entry) = passwords.get_mut(login) {
+                entry.password = hash;
+            }
+            let mut logins: Vec<_> = passwords.keys().collect();
+            logins.sort();
+            for login in logins {
+                let entry = &passwords[login];
+                content += &format!("{login} {} {}\n", entry.ip, entry.password);
+            }
+        }
+        rewrite_file(&self.filename, &mut guard, content).await
     }
+
+    // Returns a new token, which could be used instead of the password.
+    pub async fn create_token(&self, login: &str, password: &str) -> anyhow::Result<String> {
+        self.verify_password(login, password).await?;
+        let mut bytes = [0u8; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        let token = to_hex(&bytes);
+        let token_hash = hash_token(&token);
+        self.tokens
+            .lock()
+            .await
+            .insert(token_hash.clone(), login.to_string());
+        append_line(&self.tokens_file, format!("{login} {token_hash}\n")).await?;
+        log::info!("Created a new token for {login}.");
+        Ok(token)
+    }
+
+    pub async fn revoke_token(
+        &self,
+        login: &str,
+        password: &str,
+        token: &str,
+    ) -> anyhow::Result<()> {
+        self.verify_password(login, password).await?;
+        let token_hash = hash_token(token);
+        let mut guard = self.tokens_file.lock().await;
+        let mut content = String::new();
+        {
+            let mut tokens = self.tokens.lock().await;
+            if tokens.get(&token_hash).map(|s| s.as_str()) != Some(login) {
+                anyhow::bail!("Unknown token.");
+            }
+            tokens.remove(&token_hash);
+            let mut hashes: Vec<_> = tokens.keys().collect();
+            hashes.sort();
+            for token_hash in hashes {
+                content += &format!("{} {token_hash}\n", tokens[token_hash]);
+            }
+        }
+        rewrite_file(&self.tokens_filename, &mut guard, content).await?;
+        log::info!("Revoked a token of {login}.");
+        Ok(())
+    }
+
+    // Returns the login the token belongs to.
+    pub async fn check_token(&self, token: &str) -> anyhow::Result<String> {
+        match self.tokens.lock().await.get(&hash_token(token)) {
+            Some(login) => Ok(login.clone()),
+            None => anyhow::bail!("Unknown token."),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_filenames(name: &str) -> (path::PathBuf, String, String) {
+    let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    let passwords = dir.join("passwords.txt").to_str().unwrap().to_string();
+    let tokens = dir.join("tokens.txt").to_str().unwrap().to_string();
+    (dir, passwords, tokens)
+}
+
+#[tokio::test]
+async fn migrate_plaintext_passwords() {
+    let (dir, filename, tokens_filename) = test_filenames("passwords-test");
+    create_dir_all(&dir).await.unwrap();
+    tokio::fs::write(&filename, "alice 1.2.3.4 secret\n")
+        .await
+        .unwrap();
+
+    let manager = PasswordManager::new(filename.clone(), tokens_filename.clone())
+        .await
+        .unwrap();
+    assert!(manager
+        .check_password("alice", "wrong", "ip")
+        .await
+        .is_err());
+    manager
+        .check_password("alice", "secret", "ip")
+        .await
+        .unwrap();
+    manager
+        .check_password("bob", "hunter2", "ip")
+        .await
+        .unwrap();
+
+    let content = tokio::fs::read_to_string(&filename).await.unwrap();
+    assert!(!content.contains("secret") && !content.contains("hunter2"));
+    assert!(content.starts_with("alice 1.2.3.4 $argon2"));
+
+    let manager = PasswordManager::new(filename, tokens_filename)
+        .await
+        .unwrap();
+    manager
+        .check_password("alice", "secret", "ip")
+        .await
+        .unwrap();
+    manager
+        .check_password("bob", "hunter2", "ip")
+        .await
+        .unwrap();
+    assert!(manager.check_password("bob", "secret", "ip").await.is_err());
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn accounts_and_tokens() {
+    let (dir, filename, tokens_filename) = test_filenames("tokens-test");
+    let manager = PasswordManager::new(filename.clone(), tokens_filename.clone())
+        .await
+        .unwrap();
+    manager.register("alice", "secret", "ip").await.unwrap();
+    assert!(manager.register("alice", "other", "ip").await.is_err());
+    assert!(manager.create_token("alice", "wrong").await.is_err());
+    let token = manager.create_token("alice", "secret").await.unwrap();
+    let other_token = manager.create_token("alice", "secret").await.unwrap();
+    assert_eq!(manager.check_token(&token).await.unwrap(), "alice");
+
+    manager
+        .change_password("alice", "secret", "new-secret")
+        .await
+        .unwrap();
+    assert!(manager.verify_password("alice", "secret").await.is_err());
+    let long_password = "x".repeat(MAX_PASSWORD_LEN + 1);
+    assert!(manager
+        .verify_password("alice", &long_password)
+        .await
+        .is_err());
+    manager
+        .revoke_token("alice", "new-secret", &token)
+        .await
+        .unwrap();
+    assert!(manager.check_token(&token).await.is_err());
+
+    let tokens = tokio::fs::read_to_string(&tokens_filename).await.unwrap();
+    assert!(!tokens.contains(&token) && !tokens.contains(&other_token));
+
+    let manager = PasswordManager::new(filename, tokens_filename)
+        .await
+        .unwrap();
+    manager
+        .check_password("alice", "new-secret", "ip")
+        .await
+        .unwrap();
+    assert!(manager.check_token(&token).await.is_err());
+    assert_eq!(manager.check_token(&other_token).await.unwrap(), "alice");
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn register_same_login_at_once() {
+    let (dir, filename, tokens_filename) = test_filenames("concurrent-test");
+    let manager = PasswordManager::new(filename, tokens_filename)
+        .await
+        .unwrap();
+    let (first, second) = tokio::join!(
+        manager.check_password("alice", "secret", "ip"),
+        manager.check_password("alice", "secret", "ip")
+    );
+    first.unwrap();
+    second.unwrap();
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
 }
@@ -0,0 +1,294 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+// Limits for each IP address, 0 means no limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_new_connections_per_minute: usize,
+    // Checked by `Connection` for each received line, shared by all connections from the IP.
+    pub max_commands_per_second: u32,
+}
+
+#[derive(Default)]
+struct IpState {
+    active: usize,
+    // Times of connections during the last minute.
+    recent: VecDeque<Instant>,
+    commands: Option<CommandBucket>,
+}
+
+impl IpState {
+    fn forget_old(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= MINUTE)
+        {
+            self.recent.pop_front();
+        }
+    }
+}
+
+pub struct RateLimiter {
+    pub limits: Limits,
+    ips: Mutex<HashMap<IpAddr, IpState>>,
+}
+
+// Counts the connection as active until dropped.
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    limiter: Arc<RateLimiter>,
+}
+
+impl ConnectionPermit {
+    // Counts one command from the IP of the permit.
+    pub fn check_command(&self) -> Result<()> {
+        self.limiter.check_command(self.ip)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut ips = self.limiter.ips.lock().unwrap();
+        if let Some(state) = ips.get_mut(&self.ip) {
+            state.active -= 1;
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            ips: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn check_command(&self, ip: IpAddr) -> Result<()> {
+        let per_second = self.limits.max_commands_per_second;
+        if per_second == 0 {
+            return Ok(());
+        }
+        let mut ips = self.ips.lock().unwrap();
+        let state = ips.entry(ip).or_default();
+        state
+            .commands
+            .get_or_insert_with(|| CommandBucket::new(per_second))
+            .check(per_second)
+    }
+
+    pub fn try_connect(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit> {
+        let now = Instant::now();
+        let mut ips = self.ips.lock().unwrap();
+        ips.retain(|_, state| {
+            state.forget_old(now);
+            state.active > 0 || !state.recent.is_empty()
+        });
+        let state = ips.entry(ip).or_default();
+        let limits = &self.limits;
+        if limits.max_connections != 0 && state.active >= limits.max_connections {
+            anyhow::bail!(
+                "Too many connections from your IP, at most {} at the same time are allowed",
+                limits.max_connections
+            );
+        }
+        if limits.max_new_connections_per_minute != 0
+            && state.recent.len() >= limits.max_new_connections_per_minute
+        {
+            anyhow::bail!(
+                "Too many new connections from your IP, at most {} per minute are allowed. \
+                Please sleep for a couple of seconds before reconnecting",
+                limits.max_new_connections_per_minute
+            );
+        }
+        state.active += 1;
+        state.recent.push_back(now);
+        Ok(ConnectionPermit {
+            ip,
+            limiter: self.clone(),
+        })
+    }
+}
+
+// Accepts the next connection which is within the limits. Others get the error message and are closed.
+pub async fn accept(
+    listener: &TcpListener,
+    limiter: &Arc<RateLimiter>,
+) -> Result<(TcpStream, SocketAddr, ConnectionPermit)> {
+    loop {
+        let (mut tcp_stream, addr) = listener.accept().await?;
+        match limiter.try_connect(addr.ip()) {
+            Ok(permit) => return Ok((tcp_stream, addr, permit)),
+            Err(err) => {
+                log::info!("Rejected connection from {addr:?}: {err}");
+                tokio::spawn(async move {
+                    // The client could be already disconnected, it is fine.
+                    let _ = tcp_stream.write_all(format!("{err}\n").as_bytes()).await;
+                });
+            }
+        }
+    }
+}
+
+// Token bucket: allows bursts of up to `per_second` commands.
+struct CommandBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl CommandBucket {
+    fn new(per_second: u32) -> Self {
+        Self {
+            tokens: per_second as f64,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn check(&mut self, per_second: u32) -> Result<()> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+        let max_tokens = per_second as f64;
+        self.tokens = (self.tokens + elapsed * max_tokens).min(max_tokens);
+        if self.tokens < 1.0 {
+            anyhow::bail!(
+                "Too many commands from your IP, at most {per_second} per second are allowed"
+            );
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+async fn read_line(stream: &mut TcpStream) -> String {
+    use tokio::io::AsyncReadExt;
+
+    let mut res = vec![];
+    let mut byte = [0u8];
+    while stream.read(&mut byte).await.unwrap() == 1 && byte[0] != b'\n' {
+        res.push(byte[0]);
+    }
+    String::from_utf8(res).unwrap()
+}
+
+#[test]
+fn connection_limits() {
+    let limiter = RateLimiter::new(Limits {
+        max_connections: 2,
+        max_new_connections_per_minute: 3,
+        max_commands_per_second: 0,
+    });
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    let other_ip: IpAddr = "1.2.3.5".parse().unwrap();
+    let first = limiter.try_connect(ip).unwrap();
+    let _second = limiter.try_connect(ip).unwrap();
+    assert!(limiter.try_connect(ip).is_err());
+    let _other = limiter.try_connect(other_ip).unwrap();
+    drop(first);
+    let third = limiter.try_connect(ip).unwrap();
+    drop(third);
+    // Only three new connections per minute.
+    assert!(limiter.try_connect(ip).is_err());
+}
+
+#[tokio::test]
+async fn rejected_connections_get_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let limiter = RateLimiter::new(Limits {
+        max_connections: 1,
+        max_new_connections_per_minute: 0,
+        max_commands_per_second: 0,
+    });
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        loop {
+            connections.push(accept(&listener, &limiter).await.unwrap());
+        }
+    });
+
+    let _first = TcpStream::connect(addr).await.unwrap();
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    assert!(read_line(&mut second).await.contains("at the same time"));
+}
+
+#[test]
+fn commands_are_limited_per_ip() {
+    let limiter = RateLimiter::new(Limits {
+        max_connections: 0,
+        max_new_connections_per_minute: 0,
+        max_commands_per_second: 10,
+    });
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    let first = limiter.try_connect(ip).unwrap();
+    let second = limiter.try_connect(ip).unwrap();
+    let other = limiter.try_connect("1.2.3.5".parse().unwrap()).unwrap();
+    for _ in 0..5 {
+        first.check_command().unwrap();
+        second.check_command().unwrap();
+    }
+    assert!(first.check_command().is_err());
+    assert!(second.check_command().is_err());
+    other.check_command().unwrap();
+}
+
+#[tokio::test]
+async fn command_limits() {
+    use crate::connection::Connection;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let limiter = RateLimiter::new(Limits {
+        max_connections: 0,
+        max_new_connections_per_minute: 0,
+        max_commands_per_second: 10,
+    });
+    tokio::spawn(async move {
+        let (stream, addr, permit) = accept(&listener, &limiter).await.unwrap();
+        let mut conn = Connection::new(stream, addr);
+        conn.set_permit(permit);
+        while conn.read_token().await.is_ok() {}
+    });
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    for _ in 0..10 {
+        client.write_all(b"CONFIG\n").await.unwrap();
+    }
+    client.write_all(&b"GO 1 2\n".repeat(20)).await.unwrap();
+    assert!(read_line(&mut client).await.contains("Too many commands"));
+}
+
+#[tokio::test]
+async fn long_lines_are_rejected() {
+    use crate::connection::{Connection, MAX_LINE_LEN};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, addr) = listener.accept().await.unwrap();
+        let mut conn = Connection::new(stream, addr);
+        while conn.read_token().await.is_ok() {}
+    });
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(&vec![b'A'; MAX_LINE_LEN + 1])
+        .await
+        .unwrap();
+    assert!(read_line(&mut client).await.contains("Line is too long"));
+}
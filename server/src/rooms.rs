@@ -0,0 +1,242 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use game_common::{
+    consts::MAX_LOGIN_LEN, game_config::GameConfig, game_state::GameState, state_delta::DeltaCache,
+};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+
+use crate::{
+    engine,
+    engine::{Checkpoint, EngineCommand, PlayerEvent},
+    top_results::TopResults,
+};
+
+// Everyone plays here unless they ask for another room.
+pub const DEFAULT_ROOM: &str = "main";
+pub const MAX_ROOMS: usize = 20;
+
+#[derive(Clone)]
+pub struct Room {
+    pub rx_game_states: watch::Receiver<Option<GameState>>,
+    pub tx_events: mpsc::Sender<PlayerEvent>,
+    pub tx_commands: mpsc::Sender<EngineCommand>,
+    // Rules of the next game, could be changed by the admin.
+    pub config: GameConfig,
+    // Shared by WebSocket spectators of the room.
+    pub spectator_deltas: DeltaCache,
+}
+
+pub type Rooms = Mutex<HashMap<String, Room>>;
+
+// Each room runs its own sequence of games. Rooms are created when somebody plays
+// in them for the first time, and are closed after a game without players.
+pub struct RoomManager {
+    rooms: Arc<Rooms>,
+    games_dir: String,
+    // All rooms use the same rules.
+    config: GameConfig,
+}
+
+fn validate_room_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_LOGIN_LEN {
+        anyhow::bail!("Room name should be from 1 to {MAX_LOGIN_LEN} characters long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!("Room name could only contain letters, digits, '-' and '_'");
+    }
+    Ok(())
+}
+
+// Called by the engine after a game without players. The room is kept while somebody
+// still holds its states receiver: spectators, or players who are just joining.
+pub async fn close_if_unused(
+    rooms: &Rooms,
+    name: &str,
+    tx_game_states: &watch::Sender<Option<GameState>>,
+) -> bool {
+    let mut rooms = rooms.lock().await;
+    // One receiver is kept by the room itself.
+    if tx_game_states.receiver_count() > 1 {
+        return false;
+    }
+    rooms.remove(name);
+    true
+}
+
+// Rooms other than the default one get `rooms`, so they could close themselves.
+fn start_room(
+    name: &str,
+    games_dir: &str,
+    config: &GameConfig,
+    resumed: Option<Checkpoint>,
+    top_results: Option<Arc<Mutex<TopResults>>>,
+    rooms: Option<Arc<Rooms>>,
+) -> Room {
+    let (tx_game_states, rx_game_states) = watch::channel::<Option<GameState>>(None);
+    let (tx_events, rx_events) = mpsc::channel::<PlayerEvent>(1024);
+    let (tx_commands, rx_commands) = mpsc::channel::<EngineCommand>(16);
+    // Rules changed by the admin before the restart are used instead of the server ones.
+    let config = resumed
+        .as_ref()
+        .and_then(|checkpoint| checkpoint.room_config.as_ref())
+        .unwrap_or(config)
+        .clone();
+    let name = name.to_owned();
+    let games_dir = games_dir.to_owned();
+    let engine_config = config.clone();
+    tokio::spawn(async move {
+        if let Err(err) = engine::run(
+            &name,
+            tx_game_states,
+            rx_events,
+            rx_commands,
+            &games_dir,
+            engine_config,
+            resumed,
+            top_results,
+            rooms,
+        )
+        .await
+        {
+            log::error!("Engine for room {name} stopped: {err}");
+        }
+    });
+    Room {
+        rx_game_states,
+        tx_events,
+        tx_commands,
+        config,
+        spectator_deltas: DeltaCache::default(),
+    }
+}
+
+// Games saved on shutdown, one for each room. Broken checkpoints are renamed,
+// so they could be inspected and are not read again on the next restart.
+fn read_checkpoints(games_dir: &str) -> HashMap<String, Checkpoint> {
+    let mut res = HashMap::new();
+    let entries = std::fs::read_dir(engine::checkpoints_dir(games_dir));
+    for entry in entries.into_iter().flatten().flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if path.extension().is_none_or(|ext| ext != "txt") || validate_room_name(name).is_err() {
+            continue;
+        }
+        match engine::read_checkpoint(&path) {
+            Ok(checkpoint) => {
+                res.insert(name.to_owned(), checkpoint);
+            }
+            Err(err) => {
+                log::error!("Failed to read checkpoint of room {name}, starting a new game: {err}");
+                if let Err(err) = std::fs::rename(&path, path.with_extension("broken")) {
+                    log::error!("Failed to rename broken checkpoint of room {name}: {err}");
+                }
+            }
+        }
+    }
+    res
+}
+
+impl RoomManager {
+    // Only games in the default room are counted in the top results.
+    pub fn new(games_dir: String, config: GameConfig, top_results: Arc<Mutex<TopResults>>) -> Self {
+        let mut checkpoints = read_checkpoints(&games_dir);
+        let default_room = start_room(
+            DEFAULT_ROOM,
+            &games_dir,
+            &config,
+            checkpoints.remove(DEFAULT_ROOM),
+            Some(top_results),
+            None,
+        );
+        let rooms = Arc::new(Mutex::new(HashMap::from([(
+            DEFAULT_ROOM.to_owned(),
+            default_room,
+        )])));
+        let mut restored = rooms.try_lock().expect("Nobody else uses new rooms");
+        // Other rooms which had a game running when the server stopped.
+        for (name, checkpoint) in checkpoints {
+            log::info!("Restoring room {name}");
+            let room = start_room(
+                &name,
+                &games_dir,
+                &config,
+                Some(checkpoint),
+                None,
+                Some(rooms.clone()),
+            );
+            restored.insert(name, room);
+        }
+        drop(restored);
+        Self {
+            rooms,
+            games_dir,
+            config,
+        }
+    }
+
+    // Only `PLAY` creates rooms, otherwise spectators could use up `MAX_ROOMS`.
+    pub async fn get_or_create(&self, name: &str) -> Result<Room> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room) = rooms.get(name) {
+            return Ok(room.clone());
+        }
+        validate_room_name(name)?;
+        if rooms.len() >= MAX_ROOMS {
+            anyhow::bail!("Too many rooms, MAX_ROOMS = {MAX_ROOMS}");
+        }
+        log::info!("Creating room {name}");
+        let room = start_room(
+            name,
+            &self.games_dir,
+            &self.config,
+            None,
+            None,
+            Some(self.rooms.clone()),
+        );
+        rooms.insert(name.to_owned(), room.clone());
+        Ok(room)
+    }
+
+    // Unlike `get_or_create`, doesn't create new rooms.
+    pub async fn get(&self, name: &str) -> Result<Room> {
+        match self.rooms.lock().await.get(name) {
+            Some(room) => Ok(room.clone()),
+            None => anyhow::bail!("Room {name} doesn't exist"),
+        }
+    }
+
+    // Rules are applied starting from the next game in the room.
+    pub async fn set_config(&self, name: &str, config: GameConfig) -> Result<()> {
+        // Commands are read only between turns, so the lock is not held while waiting for the queue.
+        let tx_commands = self.get(name).await?.tx_commands;
+        tx_commands
+            .send(EngineCommand::SetConfig(Box::new(config.clone())))
+            .await?;
+        if let Some(room) = self.rooms.lock().await.get_mut(name) {
+            room.config = config;
+        }
+        Ok(())
+    }
+
+    // Saves games in all rooms, so they continue after the restart.
+    pub async fn shutdown(&self) {
+        let rooms: Vec<_> = self.rooms.lock().await.clone().into_iter().collect();
+        let saved = rooms.into_iter().map(|(name, room)| async move {
+            let (tx_done, rx_done) = oneshot::channel();
+            let sent = room
+                .tx_commands
+                .send(EngineCommand::Shutdown(tx_done))
+                .await;
+            if sent.is_err() || rx_done.await.is_err() {
+                log::error!("Failed to save the game in room {name}");
+            }
+        });
+        futures_util::future::join_all(saved).await;
+    }
+}
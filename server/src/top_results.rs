@@ -1,6 +1,6 @@
 use std::{collections::HashSet, path};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use game_common::game_state::GameResults;
 use tokio::{
     fs::{create_dir_all, OpenOptions},
@@ -19,33 +19,50 @@ pub struct TopResults {
     filename: String,
 }
 
+async fn read_results(filename: &str) -> anyhow::Result<Vec<UserResult>> {
+    create_dir_all(path::Path::new(filename).parent().unwrap()).await?;
+    let mut results = vec![];
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(filename)
+        .await
+        .context(format!("Open top-results file: {filename}"))?;
+    let mut lines = String::new();
+    file.read_to_string(&mut lines).await?;
+    // The file could be edited by hand and reloaded, so broken lines are errors, not panics.
+    for (i, line) in lines.lines().enumerate() {
+        let line_number = i + 1;
+        let parts: Vec<_> = line.split(' ').collect();
+        let [user, game_id, score] = parts[..] else {
+            bail!("{filename}:{line_number}: expected '[USER] [GAME_ID] [SCORE]', got '{line}'");
+        };
+        let score = score
+            .parse()
+            .context(format!("{filename}:{line_number}: wrong score '{score}'"))?;
+        results.push(UserResult {
+            score,
+            game_id: game_id.to_owned(),
+            user: user.to_owned(),
+        })
+    }
+    Ok(results)
+}
+
 impl TopResults {
     pub async fn new(filename: String) -> anyhow::Result<Self> {
-        create_dir_all(path::Path::new(&filename).parent().unwrap()).await?;
-        let mut results = vec![];
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename.clone())
-            .await
-            .context(format!("Open top-results file: {filename}"))?;
-        let mut lines = String::new();
-        file.read_to_string(&mut lines).await?;
-        for line in lines.lines() {
-            let mut parts = line.split(' ');
-            let user = parts.next().unwrap().to_owned();
-            let game_id: String = parts.next().unwrap().to_owned();
-            let score = parts.next().unwrap().parse()?;
-            results.push(UserResult {
-                score,
-                game_id,
-                user,
-            })
-        }
+        let results = read_results(&filename).await?;
         Ok(Self { results, filename })
     }
 
+    // Reads the file again, e.g. after it was edited by hand. Returns the number of results.
+    pub async fn reload(&mut self) -> anyhow::Result<usize> {
+        self.results = read_results(&self.filename).await?;
+        Ok(self.results.len())
+    }
+
     pub async fn add_results(&mut self, game_result: GameResults) -> anyhow::Result<()> {
         for player in game_result.players.iter() {
             self.results.push(UserResult {
@@ -71,6 +88,7 @@ impl TopResults {
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(self.filename.clone())
             .await?;
         for result in &self.results {
@@ -82,3 +100,25 @@ impl TopResults {
         Ok(())
     }
 }
+
+#[tokio::test]
+async fn broken_lines_are_errors() {
+    let dir = std::env::temp_dir().join(format!("top-results-test-{}", std::process::id()));
+    let filename = dir.join("top_results.txt").to_str().unwrap().to_string();
+    create_dir_all(&dir).await.unwrap();
+    tokio::fs::write(&filename, "alice game-1 10\n")
+        .await
+        .unwrap();
+    let mut top_results = TopResults::new(filename.clone()).await.unwrap();
+
+    for broken in ["bob game-2", "bob game-2 ten", "", "bob game-2 10 extra"] {
+        tokio::fs::write(&filename, format!("alice game-1 10\n{broken}\n"))
+            .await
+            .unwrap();
+        let err = top_results.reload().await.unwrap_err();
+        assert!(format!("{err:#}").contains(":2:"), "{err:#}");
+    }
+    // The old results are kept if the file can't be read.
+    assert_eq!(top_results.results.len(), 1);
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
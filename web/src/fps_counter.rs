@@ -8,6 +8,12 @@ pub struct FpsCounter {
 
 const SECS: f64 = 2.0;
 
+impl Default for FpsCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FpsCounter {
     pub fn new() -> Self {
         Self {
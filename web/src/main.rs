@@ -1,4 +1,5 @@
 pub mod fps_counter;
+pub mod replay_viewer;
 pub mod state_approximator;
 pub mod web_app;
 
@@ -26,5 +27,5 @@ fn main() {
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
-    assert!(false);
+    panic!("game-web only runs in the browser, build it with trunk");
 }
@@ -0,0 +1,86 @@
+use game_common::{game_state::GameState, replay::Replay};
+use instant::{Duration, SystemTime};
+
+use crate::state_approximator::interpolate;
+
+// Live games make one turn every 0.5s, replays use the same pace with speed 1.0.
+const TURNS_PER_SECOND: f64 = 2.0;
+
+pub struct ReplayViewer {
+    pub game_id: String,
+    states: Vec<GameState>,
+    // Fractional index in `states`, the fraction is used for interpolation.
+    pos: f64,
+    pub playing: bool,
+    pub speed: f64,
+    last_update: SystemTime,
+}
+
+impl ReplayViewer {
+    pub fn new(replay: Replay) -> Option<Self> {
+        let states: Vec<_> = replay.turns.into_iter().map(|turn| turn.state).collect();
+        Some(Self {
+            game_id: states.first()?.game_id.clone(),
+            states,
+            pos: 0.0,
+            playing: true,
+            speed: 1.0,
+            last_update: SystemTime::now(),
+        })
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn cur_idx(&self) -> usize {
+        self.pos as usize
+    }
+
+    pub fn set_idx(&mut self, idx: usize) {
+        self.pos = idx.min(self.states.len() - 1) as f64;
+    }
+
+    pub fn toggle_play(&mut self) {
+        if !self.playing && self.cur_idx() + 1 == self.states.len() {
+            self.pos = 0.0;
+        }
+        self.playing = !self.playing;
+    }
+
+    pub fn step(&mut self, delta: i64) {
+        self.playing = false;
+        let idx = (self.cur_idx() as i64 + delta).max(0);
+        self.set_idx(idx as usize);
+    }
+
+    // Should be called once per frame.
+    pub fn update(&mut self) {
+        let cur_time = SystemTime::now();
+        let elapsed = cur_time
+            .duration_since(self.last_update)
+            .unwrap_or(Duration::ZERO)
+            .as_secs_f64();
+        self.last_update = cur_time;
+        if self.playing {
+            let last = (self.states.len() - 1) as f64;
+            self.pos += elapsed * TURNS_PER_SECOND * self.speed;
+            if self.pos >= last {
+                self.pos = last;
+                self.playing = false;
+            }
+        }
+    }
+
+    pub fn get_state(&self) -> GameState {
+        let idx = self.cur_idx();
+        if idx + 1 >= self.states.len() {
+            return self.states[idx].clone();
+        }
+        interpolate(
+            &self.states[idx],
+            &self.states[idx + 1],
+            self.pos - idx as f64,
+        )
+    }
+}
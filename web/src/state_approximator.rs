@@ -3,6 +3,7 @@ use std::collections::VecDeque;
 use game_common::{
     game_state::{GameState, Item, Player},
     point::Point,
+    state_delta::{SpectatorDecoder, SpectatorMessage},
 };
 use instant::{Duration, SystemTime};
 use wasm_bindgen::prelude::wasm_bindgen;
@@ -13,12 +14,28 @@ pub struct StateWithTime {
     pub timestamp: SystemTime,
 }
 
+pub struct MessageWithTime {
+    pub message: SpectatorMessage,
+    pub timestamp: SystemTime,
+}
+
 #[derive(Default)]
 pub struct StateApproximator {
     states: VecDeque<StateWithTime>,
+    decoder: SpectatorDecoder,
 }
 
 impl StateApproximator {
+    pub fn add_message(&mut self, message: MessageWithTime) {
+        match self.decoder.decode(message.message) {
+            Ok(state) => self.add_state(StateWithTime {
+                state,
+                timestamp: message.timestamp,
+            }),
+            Err(err) => log(&format!("Failed to apply state delta: {err:?}")),
+        }
+    }
+
     pub fn add_state(&mut self, mut state: StateWithTime) {
         const OFFSET: Duration = Duration::from_secs(2);
         state.timestamp = state
@@ -86,55 +103,78 @@ impl StateApproximator {
             .unwrap_or(Duration::ZERO)
             .as_secs_f64();
         let pos = if delta == 0.0 { 0.0 } else { cur_delta / delta };
-        let players: Vec<Player> = prev
-            .state
-            .players
-            .iter()
-            .map(|player| {
-                let next_player = next.state.players.iter().find(|p| p.name == player.name);
-                if let Some(next_player) = next_player {
-                    let mut new_player = player.clone();
-                    new_player.pos = Point {
-                        x: (pos * next_player.pos.x as f64 + (1.0 - pos) * player.pos.x as f64)
-                            as i32,
-                        y: (pos * next_player.pos.y as f64 + (1.0 - pos) * player.pos.y as f64)
-                            as i32,
-                    };
-                    new_player
-                } else {
-                    player.clone()
-                }
-            })
-            .collect();
-        let items: Vec<Item> = prev
-            .state
-            .items
-            .iter()
-            .filter_map(|item| {
-                if next.state.items.contains(item) {
-                    return Some(item.clone());
-                }
-                for p in players.iter() {
-                    if item.intersects(p) {
-                        return None;
-                    }
-                }
-                Some(item.clone())
-            })
-            .collect();
-        let fake_state = GameState {
-            width: prev.state.width,
-            height: prev.state.height,
-            turn: prev.state.turn,
-            max_turns: prev.state.max_turns,
-            players,
-            items,
-            game_id: prev.state.game_id.clone(),
-        };
-        return Some(fake_state);
+        Some(interpolate(&prev.state, &next.state, pos))
     }
 }
 
+// State in between `prev` and `next`, `pos` is from 0.0 (prev) to 1.0 (next).
+pub fn interpolate(prev: &GameState, next: &GameState, pos: f64) -> GameState {
+    let players: Vec<Player> = prev
+        .players
+        .iter()
+        .map(|player| {
+            let next_player = next.players.iter().find(|p| p.name == player.name);
+            if let Some(next_player) = next_player {
+                let mut new_player = player.clone();
+                new_player.pos = Point {
+                    x: (pos * next_player.pos.x as f64 + (1.0 - pos) * player.pos.x as f64) as i32,
+                    y: (pos * next_player.pos.y as f64 + (1.0 - pos) * player.pos.y as f64) as i32,
+                };
+                new_player
+            } else {
+                player.clone()
+            }
+        })
+        .collect();
+    let items: Vec<Item> = prev
+        .items
+        .iter()
+        .filter_map(|item| {
+            if next.items.contains(item) {
+                return Some(item.clone());
+            }
+            // Moving items are still there if something of the same kind is not further than their speed.
+            let moved = item.speed != Point::ZERO
+                && next.items.iter().any(|next_item| {
+                    next_item.kind == item.kind
+                        && (next_item.pos - item.pos).len() <= item.speed.len()
+                });
+            let mut item = item.clone();
+            item.pos = Point {
+                x: predict_coord(item.pos.x, item.speed.x, item.radius, prev.width, pos),
+                y: predict_coord(item.pos.y, item.speed.y, item.radius, prev.height, pos),
+            };
+            if moved {
+                return Some(item);
+            }
+            for p in players.iter() {
+                if item.intersects(p) {
+                    return None;
+                }
+            }
+            Some(item)
+        })
+        .collect();
+    let mut fake_state = prev.clone();
+    fake_state.players = players;
+    fake_state.items = items;
+    fake_state
+}
+
+// Coordinate of a moving item after `pos` of the turn, it bounces off the sides as on the server.
+fn predict_coord(coord: i32, speed: i32, radius: i32, size: i32, pos: f64) -> i32 {
+    let (min, max) = (radius as f64, (size - radius) as f64);
+    let coord = coord as f64 + speed as f64 * pos;
+    let coord = if coord < min {
+        2.0 * min - coord
+    } else if coord > max {
+        2.0 * max - coord
+    } else {
+        coord
+    };
+    coord as i32
+}
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
@@ -11,7 +11,11 @@ use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 use game_common::{
     game_state::{GameState, Player},
+    item_kind::ItemKind,
+    obstacle::Obstacle,
     point::Point,
+    replay::Replay,
+    state_delta::SpectatorMessage,
 };
 use instant::SystemTime;
 use poll_promise::Promise;
@@ -19,7 +23,7 @@ use wasm_bindgen::{
     prelude::{wasm_bindgen, Closure},
     JsCast,
 };
-use wasm_bindgen_futures::spawn_local;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 
 #[derive(PartialEq, Eq)]
 enum SortBy {
@@ -28,7 +32,7 @@ enum SortBy {
 }
 
 pub struct App {
-    receiver: UnboundedReceiver<Option<StateWithTime>>,
+    receiver: UnboundedReceiver<Option<MessageWithTime>>,
     state_approximator: StateApproximator,
     fps_counter: FpsCounter,
     show_users: HashMap<String, bool>,
@@ -37,49 +41,67 @@ pub struct App {
     server_url: String,
     connected: bool,
     top_results_promise: Promise<Vec<Player>>,
+    replay_sender: UnboundedSender<Result<Replay, String>>,
+    replay_receiver: UnboundedReceiver<Result<Replay, String>>,
+    replay_viewer: Option<ReplayViewer>,
+    replay_url: String,
+    replay_error: Option<String>,
 }
 
-use web_sys::{CloseEvent, MessageEvent, WebSocket};
+use web_sys::{BinaryType, CloseEvent, Event, HtmlInputElement, MessageEvent, WebSocket};
 
 use crate::{
     fps_counter::FpsCounter,
-    state_approximator::{StateApproximator, StateWithTime},
+    replay_viewer::ReplayViewer,
+    state_approximator::{MessageWithTime, StateApproximator},
 };
 
+// Replays of all games played on the server, `data/games/[GAME_ID].txt`.
+const GAMES_URL: &str = "https://aicontest.dev/games/";
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
 }
 
-fn reconnect(url: String, sender: Arc<UnboundedSender<Option<StateWithTime>>>, ctx: Arc<Context>) {
+fn reconnect(
+    url: String,
+    sender: Arc<UnboundedSender<Option<MessageWithTime>>>,
+    ctx: Arc<Context>,
+) {
     log("Connection closed, reconnecting...");
 
-    let ws = WebSocket::new(&url).unwrap();
+    // Messages are much smaller in the binary format, see `binary_codec`.
+    let ws = WebSocket::new(&format!("{url}?format=binary")).unwrap();
+    ws.set_binary_type(BinaryType::Arraybuffer);
 
     let onmessage_callback = Closure::wrap(Box::new({
         let sender = sender.clone();
-        move |e: MessageEvent| match e.data().dyn_into::<js_sys::JsString>() {
-            Ok(data) => {
+        move |e: MessageEvent| {
+            let message = if let Ok(data) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                SpectatorMessage::from_binary(&js_sys::Uint8Array::new(&data).to_vec())
+            } else if let Ok(data) = e.data().dyn_into::<js_sys::JsString>() {
                 let message: String = data.to_string().into();
-                match GameState::from_string(&message) {
-                    Ok(state) => {
-                        let state = StateWithTime {
-                            state,
-                            timestamp: SystemTime::now(),
-                        };
-                        match sender.unbounded_send(Some(state)) {
-                            Ok(()) => {}
-                            Err(err) => {
-                                log(&format!("Error sending message: {err:?}"));
-                            }
+                SpectatorMessage::from_json(&message)
+            } else {
+                log("Received message of unknown type");
+                return;
+            };
+            match message {
+                Ok(message) => {
+                    let message = MessageWithTime {
+                        message,
+                        timestamp: SystemTime::now(),
+                    };
+                    match sender.unbounded_send(Some(message)) {
+                        Ok(()) => {}
+                        Err(err) => {
+                            log(&format!("Error sending message: {err:?}"));
                         }
                     }
-                    Err(err) => log(&format!("Error parsing state: {err:?}")),
                 }
-            }
-            Err(_err) => {
-                log("Received non-string message");
+                Err(err) => log(&format!("Error parsing message: {err:?}")),
             }
         }
     }) as Box<dyn FnMut(MessageEvent)>);
@@ -101,23 +123,98 @@ fn reconnect(url: String, sender: Arc<UnboundedSender<Option<StateWithTime>>>, c
     onclose_callback.forget();
 }
 
+// `https://aicontest.dev/?room=[ROOM]` shows games from another room.
+fn room_from_page_url() -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find_map(|param| param.strip_prefix("room="))
+        .map(|room| room.to_owned())
+}
+
+fn parse_replay(text: &str) -> Result<Replay, String> {
+    Replay::from_string(text).map_err(|err| format!("Failed to parse replay: {err}"))
+}
+
+fn send_replay(sender: &UnboundedSender<Result<Replay, String>>, replay: Result<Replay, String>) {
+    if let Err(err) = sender.unbounded_send(replay) {
+        log(&format!("Error sending replay: {err:?}"));
+    }
+}
+
+fn load_replay_from_url(url: &str, sender: UnboundedSender<Result<Replay, String>>) {
+    let request = ehttp::Request::get(url);
+    ehttp::fetch(request, move |response| {
+        let replay = match response {
+            Ok(response) if response.ok => match response.text() {
+                Some(text) => parse_replay(text),
+                None => Err("Replay is not a text file".to_owned()),
+            },
+            Ok(response) => Err(format!(
+                "Failed to load replay: {} {}",
+                response.status, response.status_text
+            )),
+            Err(err) => Err(format!("Failed to load replay: {err}")),
+        };
+        send_replay(&sender, replay);
+    });
+}
+
+fn pick_replay_file(sender: UnboundedSender<Result<Replay, String>>) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let input: HtmlInputElement = document
+        .create_element("input")
+        .unwrap()
+        .dyn_into()
+        .unwrap();
+    input.set_type("file");
+    input.set_accept(".txt");
+
+    let onchange_callback = Closure::wrap(Box::new({
+        let input = input.clone();
+        move |_: Event| {
+            if let Some(file) = input.files().and_then(|files| files.get(0)) {
+                let sender = sender.clone();
+                spawn_local(async move {
+                    let replay = match JsFuture::from(file.text()).await {
+                        Ok(text) => parse_replay(&text.as_string().unwrap_or_default()),
+                        Err(err) => Err(format!("Failed to read file: {err:?}")),
+                    };
+                    send_replay(&sender, replay);
+                });
+            }
+        }
+    }) as Box<dyn FnMut(Event)>);
+
+    input.set_onchange(Some(onchange_callback.as_ref().unchecked_ref()));
+    onchange_callback.forget();
+    input.click();
+}
+
 impl App {
     /// Called once before the first frame.
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        let (sender, receiver) = mpsc::unbounded::<Option<StateWithTime>>();
+        let (sender, receiver) = mpsc::unbounded::<Option<MessageWithTime>>();
 
-        let server_url = std::option_env!("SERVER_URL").unwrap_or("ws://127.0.0.1:7878");
+        let mut server_url = std::option_env!("SERVER_URL")
+            .unwrap_or("ws://127.0.0.1:7878")
+            .to_owned();
+        if let Some(room) = room_from_page_url() {
+            server_url = format!("{}/{room}", server_url.trim_end_matches('/'));
+        }
 
         let ctx = cc.egui_ctx.clone();
 
-        spawn_local(async move {
-            reconnect(
-                server_url.to_owned(),
-                Arc::new(sender.clone()),
-                Arc::new(ctx.clone()),
-            );
+        spawn_local({
+            let server_url = server_url.clone();
+            async move {
+                reconnect(server_url, Arc::new(sender.clone()), Arc::new(ctx.clone()));
+            }
         });
 
+        let (replay_sender, replay_receiver) = mpsc::unbounded::<Result<Replay, String>>();
+
         let (sender, promise) = Promise::new();
         {
             let request = ehttp::Request::get("https://aicontest.dev/top_results.txt");
@@ -134,6 +231,8 @@ impl App {
                                 target: Point::ZERO,
                                 score: words[2].parse().unwrap(),
                                 radius: 0,
+                                boost_turns: 0,
+                                idle_turns: 0,
                             })
                         }
                     }
@@ -149,9 +248,14 @@ impl App {
             show_users: HashMap::default(),
             show_top5: true,
             sort_players_by: SortBy::Score,
-            server_url: server_url.to_owned(),
+            server_url,
             connected: false,
             top_results_promise: promise,
+            replay_sender,
+            replay_receiver,
+            replay_viewer: None,
+            replay_url: GAMES_URL.to_owned(),
+            replay_error: None,
         }
     }
 }
@@ -159,16 +263,34 @@ impl App {
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         ctx.request_repaint();
-        while let Ok(Some(state)) = self.receiver.try_next() {
-            if let Some(state) = state {
+        while let Ok(Some(message)) = self.receiver.try_next() {
+            if let Some(message) = message {
                 self.connected = true;
-                self.state_approximator.add_state(state);
+                self.state_approximator.add_message(message);
             } else {
                 self.connected = false;
             }
         }
 
-        let game_state = self.state_approximator.get_state();
+        while let Ok(Some(replay)) = self.replay_receiver.try_next() {
+            match replay.map(ReplayViewer::new) {
+                Ok(Some(viewer)) => {
+                    self.replay_viewer = Some(viewer);
+                    self.replay_error = None;
+                }
+                Ok(None) => self.replay_error = Some("Replay is empty".to_owned()),
+                Err(err) => self.replay_error = Some(err),
+            }
+        }
+
+        let live_state = self.state_approximator.get_state();
+        let game_state = match &mut self.replay_viewer {
+            Some(viewer) => {
+                viewer.update();
+                Some(viewer.get_state())
+            }
+            None => live_state,
+        };
 
         let full_width = ctx.available_rect().width();
         let side_width = full_width * 0.15;
@@ -191,7 +313,7 @@ impl eframe::App for App {
                     ));
                     ui.label(format!("#players: {}", game_state.players.len()));
                 }
-                if !self.connected {
+                if !self.connected && self.replay_viewer.is_none() {
                     ui.label(
                         RichText::new(format!("Trying to connect to {}...", self.server_url))
                             .color(Color32::RED),
@@ -201,6 +323,14 @@ impl eframe::App for App {
                 ui.checkbox(&mut self.show_top5, "Show top-5 players");
                 ui.separator();
 
+                show_replay_loader(self, ui);
+                if let Some(viewer) = &mut self.replay_viewer {
+                    if show_replay_controls(viewer, ui) {
+                        self.replay_viewer = None;
+                    }
+                }
+                ui.separator();
+
                 if let Some(top_results) = self.top_results_promise.ready() {
                     let top_results = top_results.clone();
                     if !top_results.is_empty() {
@@ -228,6 +358,54 @@ impl eframe::App for App {
     }
 }
 
+fn show_replay_loader(app: &mut App, ui: &mut egui::Ui) {
+    ui.collapsing("Replays", |ui| {
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut app.replay_url);
+            if ui.button("Load").clicked() {
+                load_replay_from_url(&app.replay_url, app.replay_sender.clone());
+            }
+        });
+        if ui.button("Open local file...").clicked() {
+            pick_replay_file(app.replay_sender.clone());
+        }
+        if let Some(err) = &app.replay_error {
+            ui.label(RichText::new(err).color(Color32::RED));
+        }
+    });
+}
+
+// Returns true if user wants to go back to the live game.
+fn show_replay_controls(viewer: &mut ReplayViewer, ui: &mut egui::Ui) -> bool {
+    ui.label(format!("Replay of {}", viewer.game_id));
+    ui.horizontal(|ui| {
+        if ui.button("<").clicked() {
+            viewer.step(-1);
+        }
+        let play_text = if viewer.playing { "Pause" } else { "Play" };
+        if ui.button(play_text).clicked() {
+            viewer.toggle_play();
+        }
+        if ui.button(">").clicked() {
+            viewer.step(1);
+        }
+    });
+    ui.add(
+        egui::Slider::new(&mut viewer.speed, 0.25..=16.0)
+            .logarithmic(true)
+            .text("speed"),
+    );
+    let mut idx = viewer.cur_idx();
+    let max_idx = viewer.num_states() - 1;
+    if ui
+        .add(egui::Slider::new(&mut idx, 0..=max_idx).text("turn"))
+        .changed()
+    {
+        viewer.set_idx(idx);
+    }
+    ui.button("Back to the live game").clicked()
+}
+
 fn calc_places(players: &[Player]) -> Vec<(Player, String)> {
     let mut res = vec![];
     let mut i = 0;
@@ -346,10 +524,28 @@ fn draw_state(app: &App, ui: &mut egui::Ui, game_state: &GameState) {
             background_color,
         );
     }
+    {
+        let color = egui::Color32::DARK_GRAY;
+        for obstacle in game_state.obstacles.iter() {
+            match *obstacle {
+                Obstacle::Circle { center, radius } => {
+                    ui.painter()
+                        .circle_filled(conv_pt(center), radius as f32 * zoom, color);
+                }
+                Obstacle::Rect { min, max } => {
+                    ui.painter().rect_filled(
+                        egui::Rect::from_two_pos(conv_pt(min), conv_pt(max)),
+                        Rounding::default(),
+                        color,
+                    );
+                }
+            }
+        }
+    }
     {
         // draw items
         for item in game_state.items.iter() {
-            let color = egui::Color32::LIGHT_BLUE;
+            let color = item_color(item.kind);
             let center = conv_pt(item.pos);
             ui.painter()
                 .circle_filled(center, item.radius as f32 * zoom, color);
@@ -368,6 +564,17 @@ fn draw_state(app: &App, ui: &mut egui::Ui, game_state: &GameState) {
     }
 }
 
+fn item_color(kind: ItemKind) -> Color32 {
+    match kind {
+        ItemKind::Regular => Color32::LIGHT_BLUE,
+        ItemKind::Gem => Color32::GOLD,
+        ItemKind::Decaying => Color32::from_rgb(150, 150, 150),
+        ItemKind::SpeedBoost => Color32::LIGHT_GREEN,
+        ItemKind::Shrink => Color32::from_rgb(255, 150, 150),
+        ItemKind::Grow => Color32::from_rgb(200, 130, 255),
+    }
+}
+
 fn draw_players(
     app: &App,
     game_state: &GameState,
@@ -427,6 +634,7 @@ fn choose_player_color(player: &Player) -> egui::Color32 {
     egui::Color32::from_rgb(r, g, b)
 }
 
+#[allow(dead_code)]
 fn draw_arrow(ui: &mut egui::Ui, from: Pos2, to: Pos2, color: egui::Color32) {
     let dir = to - from;
     let len = dir.length();